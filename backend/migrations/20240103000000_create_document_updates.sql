@@ -0,0 +1,20 @@
+-- Append-only log of CRDT updates (yrs/Yjs updates, lib0 v1 encoding)
+CREATE TABLE IF NOT EXISTS document_updates (
+    id BIGSERIAL PRIMARY KEY,
+    document_id UUID NOT NULL REFERENCES documents(id) ON DELETE CASCADE,
+    update_data BYTEA NOT NULL,
+    user_id TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Latest full CRDT state of each document; updates with id <= last_update_id are folded into it
+CREATE TABLE IF NOT EXISTS document_snapshots (
+    document_id UUID PRIMARY KEY REFERENCES documents(id) ON DELETE CASCADE,
+    state BYTEA NOT NULL,
+    version BIGINT NOT NULL,
+    last_update_id BIGINT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Rehydration reads a document's updates in order
+CREATE INDEX IF NOT EXISTS idx_document_updates_document_id ON document_updates(document_id, id);
@@ -0,0 +1,9 @@
+-- Room messages relayed between instances that are too large for a NOTIFY payload.
+-- Listeners fetch them by id; rows are only kept long enough for that.
+CREATE TABLE IF NOT EXISTS relayed_messages (
+    id BIGSERIAL PRIMARY KEY,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_relayed_messages_created_at ON relayed_messages(created_at);
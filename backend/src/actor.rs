@@ -0,0 +1,668 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::config::{CacheConfig, PersistenceConfig};
+use crate::crdt::{CRDTDocument, DocumentUpdate, LogPosition, UndoResult};
+use crate::database::Database;
+use crate::error::AppError;
+use crate::models::Document;
+
+/// Commands a document actor buffers before senders have to wait for it.
+const MAILBOX_CAPACITY: usize = 64;
+
+/// Catch-ups buffered for `DocumentActors::subscribe_catch_ups` before the
+/// oldest are dropped.
+const CATCH_UP_CAPACITY: usize = 256;
+
+type Reply<T> = oneshot::Sender<Result<T, AppError>>;
+
+/// What a document actor can be asked to do. Only the actor persists the
+/// document's changes, so the update log is written in the same order the
+/// changes were applied in memory.
+enum Command {
+    Edit {
+        content: String,
+        user_id: String,
+        ip_address: String,
+        reply: Reply<(Document, DocumentUpdate)>,
+    },
+    /// Apply an update made on this instance, recorded for its author to
+    /// undo if `undoable`
+    Apply {
+        update: DocumentUpdate,
+        undoable: bool,
+        reply: Reply<DocumentUpdate>,
+    },
+    Undo {
+        user_id: String,
+        reply: Reply<UndoResult>,
+    },
+    Redo {
+        user_id: String,
+        reply: Reply<UndoResult>,
+    },
+    /// Apply an update another instance made. That instance persists it,
+    /// before relaying it since the postgres relay requires write-through
+    /// persistence (see `AppConfig::validate`), so it isn't logged again here.
+    /// If its `logged_as` shows that something before it was missed, the
+    /// document catches up from the log instead.
+    Merge {
+        update: DocumentUpdate,
+        reply: Reply<u64>,
+    },
+    /// Merge whatever other instances logged that the document is missing
+    CatchUp(Reply<()>),
+    /// Persist all changes now instead of waiting for the flush window
+    Flush(Reply<()>),
+    Document(Reply<Document>),
+    /// Run a read-only closure against the document; it replies by itself
+    Read(Box<dyn FnOnce(&CRDTDocument) + Send>),
+}
+
+/// A document's CRDT state together with the row it belongs to.
+pub struct LoadedDocument {
+    pub uuid: Uuid,
+    pub doc: CRDTDocument,
+    /// Every update logged up to this `document_updates` id is merged into `doc`
+    pub logged_through: i64,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl LoadedDocument {
+    fn document(&self) -> Document {
+        Document {
+            id: self.doc.id.clone(),
+            content: self.doc.get_content(),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+/// Changes other instances made that a document caught up on from the log,
+/// rather than through their relayed messages, so its connections still
+/// need to be sent them.
+#[derive(Debug, Clone)]
+pub struct CaughtUp {
+    pub document_id: String,
+    pub update: DocumentUpdate,
+}
+
+/// Bookkeeping shared between an actor and its handles.
+#[derive(Debug, Default)]
+struct ActorStats {
+    /// Commands sent but not finished yet, including ones whose caller gave up
+    pending: AtomicUsize,
+    /// Estimated size of the CRDT state: its encoded size when loaded plus
+    /// every update applied since
+    size: AtomicUsize,
+    /// Whether some changes haven't been persisted yet
+    dirty: AtomicBool,
+}
+
+#[derive(Debug)]
+struct HandleInner {
+    commands: mpsc::Sender<Command>,
+    stats: Arc<ActorStats>,
+}
+
+/// Sends commands to the task that owns one document's CRDT state.
+///
+/// Each document is only ever touched by its own task, so edits to different
+/// documents run in parallel and there are no locks to order around.
+#[derive(Debug, Clone)]
+pub struct DocumentHandle {
+    inner: Arc<HandleInner>,
+}
+
+impl DocumentHandle {
+    /// Start the actor owning `loaded`. It stops once every handle is dropped.
+    fn spawn(loaded: LoadedDocument, pool: PgPool, config: PersistenceConfig, caught_up: broadcast::Sender<CaughtUp>) -> Self {
+        let (commands, mailbox) = mpsc::channel(MAILBOX_CAPACITY);
+        let stats = Arc::new(ActorStats::default());
+        stats.size.store(loaded.doc.encode_state_as_update().len(), Ordering::Relaxed);
+        let actor = Actor {
+            loaded,
+            pool,
+            stats: stats.clone(),
+            config,
+            caught_up,
+            unflushed: Vec::new(),
+            edited_from: None,
+            flush_at: None,
+            stopped: false,
+        };
+        tokio::spawn(actor.run(mailbox));
+        Self { inner: Arc::new(HandleInner { commands, stats }) }
+    }
+
+    /// Replace the document's content on behalf of `user_id`, recording it in the history.
+    pub async fn edit(&self, content: &str, user_id: &str, ip_address: &str) -> Result<(Document, DocumentUpdate), AppError> {
+        self.request(|reply| Command::Edit {
+            content: content.to_string(),
+            user_id: user_id.to_string(),
+            ip_address: ip_address.to_string(),
+            reply,
+        })
+        .await
+    }
+
+    pub async fn apply(&self, update: &DocumentUpdate, undoable: bool) -> Result<DocumentUpdate, AppError> {
+        self.request(|reply| Command::Apply { update: update.clone(), undoable, reply }).await
+    }
+
+    pub async fn undo(&self, user_id: &str) -> Result<UndoResult, AppError> {
+        self.request(|reply| Command::Undo { user_id: user_id.to_string(), reply }).await
+    }
+
+    pub async fn redo(&self, user_id: &str) -> Result<UndoResult, AppError> {
+        self.request(|reply| Command::Redo { user_id: user_id.to_string(), reply }).await
+    }
+
+    /// Bring the in-memory document up to date with an update another
+    /// instance applied and persisted, returning our version after it.
+    pub async fn merge(&self, update: &DocumentUpdate) -> Result<u64, AppError> {
+        self.request(|reply| Command::Merge { update: update.clone(), reply }).await
+    }
+
+    /// Merge what other instances logged that the document is missing.
+    pub async fn catch_up(&self) -> Result<(), AppError> {
+        self.request(Command::CatchUp).await
+    }
+
+    /// Persist the document's pending changes now.
+    pub async fn flush(&self) -> Result<(), AppError> {
+        self.request(Command::Flush).await
+    }
+
+    /// The document as stored, with its current content.
+    pub async fn document(&self) -> Result<Document, AppError> {
+        self.request(Command::Document).await
+    }
+
+    /// Read something off the document once the changes queued before this are applied.
+    pub async fn read<R: Send + 'static>(&self, f: impl FnOnce(&CRDTDocument) -> R + Send + 'static) -> Result<R, AppError> {
+        self.request(|reply| Command::Read(Box::new(move |doc| {
+            let _ = reply.send(Ok(f(doc)));
+        })))
+        .await
+    }
+
+    async fn request<T>(&self, command: impl FnOnce(Reply<T>) -> Command) -> Result<T, AppError> {
+        let (reply, response) = oneshot::channel();
+        self.inner.stats.pending.fetch_add(1, Ordering::SeqCst);
+        if self.inner.commands.send(command(reply)).await.is_err() {
+            self.inner.stats.pending.fetch_sub(1, Ordering::SeqCst);
+            return Err(actor_stopped());
+        }
+        response.await.map_err(|_| actor_stopped())?
+    }
+
+    /// Whether the cache may drop this handle: nobody else holds a copy to
+    /// send commands with, everything sent before is done, and persisted.
+    fn is_idle(&self) -> bool {
+        let stats = &self.inner.stats;
+        self.is_stopped()
+            || (Arc::strong_count(&self.inner) == 1
+                && stats.pending.load(Ordering::SeqCst) == 0
+                && !stats.dirty.load(Ordering::SeqCst))
+    }
+
+    /// Whether the actor stopped by itself, see `Actor`
+    fn is_stopped(&self) -> bool {
+        self.inner.commands.is_closed()
+    }
+
+    fn size(&self) -> usize {
+        self.inner.stats.size.load(Ordering::Relaxed)
+    }
+}
+
+fn actor_stopped() -> AppError {
+    AppError::InternalError("Document actor stopped".to_string())
+}
+
+/// How long to wait before retrying a flush that failed.
+const FLUSH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The actor itself: owns the document and persists its changes.
+///
+/// Changes are applied in memory and acknowledged right away, then written
+/// to Postgres together once `flush_window` has passed since the first of
+/// them, or as soon as too many are waiting. What is still unflushed when the
+/// last handle goes away, on eviction or shutdown, is flushed before the actor
+/// stops. Commands from a caller that gave up waiting still take effect.
+/// Without a flush window, changes are written before being acknowledged.
+///
+/// When its changes can't be persisted for good, the actor stops early and
+/// the cache lets go of it, so the document is loaded again as it is stored.
+///
+/// With several instances, it also keeps track of how far into the update log
+/// the document is, and catches up from the log whenever it may have missed
+/// an update another instance logged: when it starts, since an update relayed
+/// while it was loading is dropped, and whenever a relayed update doesn't
+/// follow on from the last one.
+struct Actor {
+    loaded: LoadedDocument,
+    pool: PgPool,
+    stats: Arc<ActorStats>,
+    config: PersistenceConfig,
+    caught_up: broadcast::Sender<CaughtUp>,
+    /// Changes applied in memory but not persisted yet, oldest first
+    unflushed: Vec<DocumentUpdate>,
+    /// Where the latest content edit among them came from, for the history
+    edited_from: Option<String>,
+    /// When `unflushed` is due to be persisted
+    flush_at: Option<Instant>,
+    /// The in-memory document can't be persisted, so it has to be loaded
+    /// again; see `flush`
+    stopped: bool,
+}
+
+impl Actor {
+    async fn run(mut self, mut mailbox: mpsc::Receiver<Command>) {
+        if let Err(e) = self.catch_up().await {
+            tracing::warn!("Document {} cannot catch up with the update log: {}", self.loaded.doc.id, e);
+        }
+        loop {
+            let flush_at = self.flush_at;
+            let command = tokio::select! {
+                command = mailbox.recv() => command,
+                _ = tokio::time::sleep_until(flush_at.unwrap_or_else(Instant::now)), if flush_at.is_some() => {
+                    self.flush_logged().await;
+                    if self.stopped {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            let Some(command) = command else { break };
+            self.handle(command).await;
+            self.stats.pending.fetch_sub(1, Ordering::SeqCst);
+            if self.stopped {
+                break;
+            }
+        }
+        self.flush_logged().await;
+    }
+
+    async fn handle(&mut self, command: Command) {
+        match command {
+            Command::Edit { content, user_id, ip_address, reply } => {
+                let update = self.loaded.doc.update_content(&content, &user_id);
+                let result = self.record(update, Some(ip_address)).await;
+                let _ = reply.send(result.map(|update| (self.loaded.document(), update)));
+            }
+            Command::Apply { update, undoable, reply } => {
+                let applied = if undoable {
+                    self.loaded.doc.apply_update(&update)
+                } else {
+                    self.loaded.doc.apply_untracked_update(&update)
+                };
+                let result = match applied {
+                    Ok(applied) => self.record(applied, None).await,
+                    Err(e) => Err(AppError::InternalError(e)),
+                };
+                let _ = reply.send(result);
+            }
+            Command::Undo { user_id, reply } => {
+                let result = self.loaded.doc.undo(&user_id);
+                let _ = reply.send(self.record_undo_result(result).await);
+            }
+            Command::Redo { user_id, reply } => {
+                let result = self.loaded.doc.redo(&user_id);
+                let _ = reply.send(self.record_undo_result(result).await);
+            }
+            Command::Merge { update, reply } => {
+                let result = self.merge(&update).await.map(|()| self.loaded.doc.version());
+                let _ = reply.send(result);
+            }
+            Command::CatchUp(reply) => {
+                let _ = reply.send(self.catch_up().await);
+            }
+            Command::Flush(reply) => {
+                let _ = reply.send(self.flush().await.map(|_| ()));
+            }
+            Command::Document(reply) => {
+                let _ = reply.send(Ok(self.loaded.document()));
+            }
+            Command::Read(f) => f(&self.loaded.doc),
+        }
+    }
+
+    /// Queue a change that was applied in memory to be persisted. Without a
+    /// flush window, or with too many changes waiting, it is flushed right away,
+    /// and returned with where it was logged.
+    /// Changes that change nothing aren't logged at all.
+    ///
+    /// With a flush window the change has taken effect once it is queued, so
+    /// a failed flush is only logged: it stays queued and is retried, and
+    /// callers still pass the change on to everyone else. Without one it only
+    /// takes effect once written, so a failed write is returned, for callers
+    /// not to pass it on, and the actor stops to drop it from memory.
+    async fn record(&mut self, mut applied: DocumentUpdate, edited_from: Option<String>) -> Result<DocumentUpdate, AppError> {
+        if applied.is_empty() {
+            return Ok(applied);
+        }
+        self.stats.size.fetch_add(applied.update.len(), Ordering::Relaxed);
+        self.stats.dirty.store(true, Ordering::SeqCst);
+        self.unflushed.push(applied.clone());
+        if edited_from.is_some() {
+            self.edited_from = edited_from;
+        }
+
+        // It is the last of the changes flushed
+        if self.config.flush_window_ms == 0 {
+            applied.logged_as = self.flush().await?;
+        } else if self.unflushed.len() >= self.config.max_unflushed_updates {
+            applied.logged_as = self.flush_logged().await;
+        } else {
+            self.flush_at.get_or_insert_with(|| Instant::now() + self.config.flush_window());
+        }
+        Ok(applied)
+    }
+
+    async fn record_undo_result(&mut self, mut result: UndoResult) -> Result<UndoResult, AppError> {
+        if let Some(applied) = result.update.take() {
+            result.update = Some(self.record(applied, None).await?);
+        }
+        Ok(result)
+    }
+
+    /// Persist every unflushed change in one transaction, returning where
+    /// the last of them was logged. On failure they stay queued and are
+    /// retried later, unless there is no point: the document was deleted,
+    /// or it is written through and the changes were refused. The actor
+    /// then drops them and stops.
+    async fn flush(&mut self) -> Result<Option<LogPosition>, AppError> {
+        self.flush_at = None;
+        if self.unflushed.is_empty() {
+            return Ok(None);
+        }
+
+        let loaded = &mut self.loaded;
+        let result = Database::persist_crdt_updates(
+            &self.pool,
+            loaded.uuid,
+            &mut loaded.doc,
+            loaded.logged_through,
+            &self.unflushed,
+            self.edited_from.as_deref(),
+        )
+        .await;
+        match result {
+            Ok(persisted) => {
+                self.loaded.updated_at = persisted.updated_at;
+                let last = persisted.positions.last().copied();
+                if let Some(last) = last {
+                    self.loaded.logged_through = last.id;
+                }
+                self.announce(persisted.caught_up);
+                self.unflushed.clear();
+                self.edited_from = None;
+                self.stats.dirty.store(false, Ordering::SeqCst);
+                Ok(last)
+            }
+            Err(e) if matches!(e, AppError::DocumentNotFound(_)) || self.config.flush_window_ms == 0 => {
+                tracing::warn!("Dropping {} unpersisted changes to document {}: {}", self.unflushed.len(), self.loaded.doc.id, e);
+                self.unflushed.clear();
+                self.edited_from = None;
+                self.stats.dirty.store(false, Ordering::SeqCst);
+                self.stopped = true;
+                Err(e)
+            }
+            Err(e) => {
+                self.flush_at = Some(Instant::now() + self.config.flush_window().max(FLUSH_RETRY_DELAY));
+                Err(e)
+            }
+        }
+    }
+
+    async fn flush_logged(&mut self) -> Option<LogPosition> {
+        let waiting = self.unflushed.len();
+        self.flush().await.unwrap_or_else(|e| {
+            tracing::error!("Failed to persist {} changes to document {}: {}", waiting, self.loaded.doc.id, e);
+            None
+        })
+    }
+
+    /// Merge an update another instance relayed, or catch up from the log if
+    /// it shows that an earlier one never reached us.
+    async fn merge(&mut self, update: &DocumentUpdate) -> Result<(), AppError> {
+        match update.logged_as {
+            // Already merged while catching up
+            Some(logged) if logged.id <= self.loaded.logged_through => Ok(()),
+            Some(logged) if logged.previous == self.loaded.logged_through => {
+                self.merge_relayed(update)?;
+                self.loaded.logged_through = logged.id;
+                Ok(())
+            }
+            // Something was logged in between; the log has it, and this one too
+            Some(_) => self.catch_up().await,
+            // Its instance couldn't log it yet, and will once it can
+            None => self.merge_relayed(update),
+        }
+    }
+
+    fn merge_relayed(&mut self, update: &DocumentUpdate) -> Result<(), AppError> {
+        self.loaded.doc.merge_update(update).map_err(AppError::InternalError)?;
+        self.stats.size.fetch_add(update.update.len(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Merge everything logged after `logged_through`.
+    async fn catch_up(&mut self) -> Result<(), AppError> {
+        let mut conn = self.pool.acquire().await?;
+        let missed = Database::read_crdt_log(&mut conn, self.loaded.uuid, self.loaded.logged_through).await?;
+        drop(conn);
+        let caught_up = self.loaded.doc.catch_up(missed.updates).map_err(AppError::InternalError)?;
+        self.loaded.logged_through = missed.head;
+        self.announce(caught_up);
+        Ok(())
+    }
+
+    /// Pass on what catching up merged into the document, for its connections.
+    fn announce(&self, caught_up: Option<DocumentUpdate>) {
+        let Some(update) = caught_up else { return };
+        self.stats.size.fetch_add(update.update.len(), Ordering::Relaxed);
+        tracing::info!("Document {} caught up with the update log", self.loaded.doc.id);
+        let _ = self.caught_up.send(CaughtUp { document_id: self.loaded.doc.id.clone(), update });
+    }
+}
+
+/// How the document cache is doing, for monitoring.
+#[derive(Debug, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheMetrics {
+    /// Documents currently loaded
+    pub documents: usize,
+    /// Estimated size of their CRDT state
+    pub bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+    entries: HashMap<String, CacheEntry>,
+    /// Bumped on every access, to order entries by recency
+    clock: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl Cache {
+    /// Forget a document whose actor stopped, for it to be loaded again.
+    fn remove_stopped(&mut self, id: &str) {
+        if self.entries.get(id).is_some_and(|entry| entry.handle.is_stopped()) {
+            self.entries.remove(id);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    handle: DocumentHandle,
+    last_used: u64,
+}
+
+/// The actors of the documents loaded into memory, by document ID: a cache
+/// bounded by `CacheConfig`, evicting the least recently used idle documents.
+///
+/// The map is only locked to look up or insert a handle, never while a
+/// document is being worked on. Documents are only evicted when idle, which
+/// includes having nothing left to flush, so a document loaded again right
+/// after its eviction can't miss changes. Busy documents may take the cache
+/// over budget until they are idle again.
+#[derive(Debug, Clone)]
+pub struct DocumentActors {
+    cache: Arc<Mutex<Cache>>,
+    config: CacheConfig,
+    persistence: PersistenceConfig,
+    /// What documents caught up on from the log, see `Actor`
+    caught_up: broadcast::Sender<CaughtUp>,
+}
+
+impl DocumentActors {
+    pub fn new(config: CacheConfig, persistence: PersistenceConfig) -> Self {
+        Self {
+            cache: Arc::new(Mutex::new(Cache::default())),
+            config,
+            persistence,
+            caught_up: broadcast::channel(CATCH_UP_CAPACITY).0,
+        }
+    }
+
+    /// Changes other instances made that loaded documents caught up on from
+    /// the log, rather than through the relay.
+    pub fn subscribe_catch_ups(&self) -> broadcast::Receiver<CaughtUp> {
+        self.caught_up.subscribe()
+    }
+
+    /// The handle of a loaded document, without counting it as an access.
+    pub fn peek(&self, id: &str) -> Option<DocumentHandle> {
+        self.cache.lock().unwrap()
+            .entries
+            .get(id)
+            .map(|entry| entry.handle.clone())
+            .filter(|handle| !handle.is_stopped())
+    }
+
+    /// The handle of a loaded document, counting a cache hit or miss.
+    pub fn get(&self, id: &str) -> Option<DocumentHandle> {
+        let mut cache = self.cache.lock().unwrap();
+        cache.remove_stopped(id);
+        cache.clock += 1;
+        let clock = cache.clock;
+        match cache.entries.get_mut(id) {
+            Some(entry) => {
+                entry.last_used = clock;
+                let handle = entry.handle.clone();
+                cache.hits += 1;
+                Some(handle)
+            }
+            None => {
+                cache.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Start an actor for `loaded` unless the document already has one, e.g.
+    /// because another task loaded it concurrently, and return the one in use.
+    /// Makes room for it by evicting idle documents if the cache is over budget.
+    pub fn spawn(&self, loaded: LoadedDocument, pool: &PgPool) -> DocumentHandle {
+        let mut cache = self.cache.lock().unwrap();
+        cache.clock += 1;
+        let clock = cache.clock;
+        let id = loaded.doc.id.clone();
+        cache.remove_stopped(&id);
+        let handle = cache.entries
+            .entry(id.clone())
+            .or_insert_with(|| CacheEntry {
+                handle: DocumentHandle::spawn(loaded, pool.clone(), self.persistence.clone(), self.caught_up.clone()),
+                last_used: clock,
+            })
+            .handle
+            .clone();
+        self.evict(&mut cache, &id);
+        handle
+    }
+
+    /// Drop least recently used idle documents, other than `keep`, until
+    /// the cache is within budget again.
+    fn evict(&self, cache: &mut Cache, keep: &str) {
+        let mut bytes: usize = cache.entries.values().map(|entry| entry.handle.size()).sum();
+        while cache.entries.len() > self.config.max_documents || bytes > self.config.max_bytes {
+            let victim = cache.entries
+                .iter()
+                .filter(|(id, entry)| id.as_str() != keep && entry.handle.is_idle())
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(id, _)| id.clone());
+            let Some(victim) = victim else { break };
+            if let Some(entry) = cache.entries.remove(&victim) {
+                bytes -= entry.handle.size();
+                cache.evictions += 1;
+                tracing::debug!("Evicted document {} from the cache", victim);
+            }
+        }
+    }
+
+    /// Persist the pending changes of every loaded document, e.g. before shutting down.
+    /// Keeps going past failures and returns the first one.
+    pub async fn flush(&self) -> Result<(), AppError> {
+        let mut result = Ok(());
+        for handle in self.handles() {
+            let flushed = handle.flush().await;
+            if result.is_ok() {
+                result = flushed;
+            }
+        }
+        result
+    }
+
+    /// Catch every loaded document up with the update log, e.g. after
+    /// relayed messages may have been lost. Keeps going past failures and
+    /// returns the first one.
+    pub async fn catch_up(&self) -> Result<(), AppError> {
+        let mut result = Ok(());
+        for handle in self.handles() {
+            let caught_up = handle.catch_up().await;
+            if result.is_ok() {
+                result = caught_up;
+            }
+        }
+        result
+    }
+
+    fn handles(&self) -> Vec<DocumentHandle> {
+        self.cache.lock().unwrap()
+            .entries
+            .values()
+            .map(|entry| entry.handle.clone())
+            .filter(|handle| !handle.is_stopped())
+            .collect()
+    }
+
+    pub fn metrics(&self) -> CacheMetrics {
+        let cache = self.cache.lock().unwrap();
+        CacheMetrics {
+            documents: cache.entries.len(),
+            bytes: cache.entries.values().map(|entry| entry.handle.size()).sum(),
+            hits: cache.hits,
+            misses: cache.misses,
+            evictions: cache.evictions,
+        }
+    }
+}
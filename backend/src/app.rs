@@ -9,16 +9,21 @@ use std::sync::Arc;
 use utoipa::OpenApi;
 
 use crate::{
-    config::AppConfig,
+    config::{AppConfig, RelayBackend},
     database::Database,
     auth::auth_middleware,
+    events::document_events_handler,
     handlers::{
-        create_document, get_document, get_document_history, get_document_stats,
-        search_documents, update_document, get_document_crdt_state, apply_crdt_update,
+        get_document, get_document_history, get_document_stats, get_cache_metrics,
+        search_documents, update_document, get_document_crdt_state, get_document_crdt_diff, apply_crdt_update,
+        undo_crdt_update, redo_crdt_update,
         signup, login, create_document_protected, update_user_role,
     },
-    websocket::{websocket_handler, websocket_info_handler, WebSocketManager},
+    multiplex::multiplex_handler,
+    relay::{LocalRelay, PostgresRelay, RoomRelay},
+    websocket::{spawn_relay_listener, websocket_handler, websocket_info_handler, websocket_metrics_handler, WebSocketManager},
     openapi::{ApiDoc, SwaggerUi},
+    poll::document_sync_handler,
 };
 
 #[derive(Clone)]
@@ -27,8 +32,34 @@ pub struct AppState {
     pub ws_manager: Arc<WebSocketManager>,
 }
 
+impl AppState {
+    /// Set up the WebSocket manager and its background tasks around `database`.
+    pub fn new(database: Database, config: &AppConfig) -> Self {
+        // Create WebSocket manager, with rooms spanning every instance if configured
+        let relay: Arc<dyn RoomRelay> = match config.websocket.relay {
+            RelayBackend::Memory => Arc::new(LocalRelay),
+            RelayBackend::Postgres => Arc::new(PostgresRelay::new(database.pool.clone(), config.websocket.channel_capacity)),
+        };
+        let ws_manager = Arc::new(WebSocketManager::with_relay(config.websocket.clone(), relay));
+        ws_manager.spawn_awareness_reaper();
+
+        // Create combined state
+        let state = AppState {
+            database,
+            ws_manager,
+        };
+        spawn_relay_listener(state.clone());
+        state
+    }
+}
+
 /// Create the application router with all routes and middleware
 pub fn create_app(database: Database, config: &AppConfig) -> Router {
+    create_app_with_state(AppState::new(database, config), config)
+}
+
+/// Like `create_app`, for callers that keep the state, e.g. to shut it down.
+pub fn create_app_with_state(state: AppState, config: &AppConfig) -> Router {
     // Setup CORS
     let cors = CorsLayer::new()
         .allow_origin(config.cors.allowed_origins.iter().map(|origin| {
@@ -47,15 +78,6 @@ pub fn create_app(database: Database, config: &AppConfig) -> Router {
             "x-requested-with".parse::<HeaderName>().unwrap(),
         ]);
 
-    // Create WebSocket manager
-    let ws_manager = Arc::new(WebSocketManager::new());
-
-    // Create combined state
-    let state = AppState {
-        database,
-        ws_manager,
-    };
-
     // Create router with all routes
     let public_routes = Router::new()
         // Public authentication routes
@@ -66,13 +88,21 @@ pub fn create_app(database: Database, config: &AppConfig) -> Router {
         .route("/api/doc/{id}", put(update_document))
         .route("/api/doc/{id}/history", get(get_document_history))
         .route("/api/doc/{id}/stats", get(get_document_stats))
+        .route("/api/doc/{id}/events", get(document_events_handler))
         .route("/api/search", get(search_documents))
+        .route("/api/cache/metrics", get(get_cache_metrics))
         // CRDT routes for real-time collaboration
         .route("/api/doc/{id}/crdt/state", get(get_document_crdt_state))
+        .route("/api/doc/{id}/crdt/diff", post(get_document_crdt_diff))
         .route("/api/doc/{id}/crdt/update", post(apply_crdt_update))
+        .route("/api/doc/{id}/crdt/undo", post(undo_crdt_update))
+        .route("/api/doc/{id}/crdt/redo", post(redo_crdt_update))
+        .route("/api/doc/{id}/crdt/sync", post(document_sync_handler))
         // WebSocket routes
         .route("/ws/doc/{document_id}", get(websocket_handler))
+        .route("/ws/multiplex", get(multiplex_handler))
         .route("/ws/info/{document_id}", get(websocket_info_handler))
+        .route("/ws/metrics", get(websocket_metrics_handler))
         // Swagger UI
         .merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", ApiDoc::openapi()));
 
@@ -110,10 +140,15 @@ pub fn create_test_app(database: Database) -> Router {
     };
 
     Router::new()
-        .route("/api/doc", post(create_document))
+        .route("/api/doc", post(crate::handlers::create_document))
         .route("/api/doc/{id}", get(get_document))
         .route("/api/doc/{id}", put(update_document))
         .route("/api/doc/{id}/history", get(get_document_history))
+        .route("/api/doc/{id}/crdt/state", get(get_document_crdt_state))
+        .route("/api/doc/{id}/crdt/diff", post(get_document_crdt_diff))
+        .route("/api/doc/{id}/crdt/update", post(apply_crdt_update))
+        .route("/api/doc/{id}/crdt/undo", post(undo_crdt_update))
+        .route("/api/doc/{id}/crdt/redo", post(redo_crdt_update))
         .layer(cors)
         .with_state(state)
 } 
\ No newline at end of file
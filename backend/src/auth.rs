@@ -18,12 +18,24 @@ use crate::{
 const JWT_SECRET: &[u8] = b"your-secret-key-change-in-production";
 const JWT_EXPIRATION_HOURS: i64 = 24;
 
+/// Token scope for viewers: their connections follow documents as
+/// spectators and can't edit them.
+pub const READ_ONLY_SCOPE: &str = "read";
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AuthenticatedUser {
     pub user_id: Uuid,
     pub email: String,
     pub role_id: i32,
     pub role_name: String,
+    pub scope: Option<String>,
+}
+
+impl AuthenticatedUser {
+    /// Whether the token only lets the user follow documents.
+    pub fn is_read_only(&self) -> bool {
+        self.scope.as_deref() == Some(READ_ONLY_SCOPE)
+    }
 }
 
 pub async fn hash_password(password: &str) -> Result<String, AppError> {
@@ -37,6 +49,11 @@ pub async fn verify_password(password: &str, hash: &str) -> Result<bool, AppErro
 }
 
 pub fn create_jwt_token(user: &User) -> Result<String, AppError> {
+    create_scoped_jwt_token(user, None)
+}
+
+/// Like `create_jwt_token`, for a token limited to `scope` (see `READ_ONLY_SCOPE`).
+pub fn create_scoped_jwt_token(user: &User, scope: Option<&str>) -> Result<String, AppError> {
     let now = Utc::now();
     let exp = now + Duration::hours(JWT_EXPIRATION_HOURS);
     
@@ -47,6 +64,7 @@ pub fn create_jwt_token(user: &User) -> Result<String, AppError> {
         role_name: user.role_name.clone(),
         exp: exp.timestamp(),
         iat: now.timestamp(),
+        scope: scope.map(str::to_string),
     };
 
     encode(
@@ -68,6 +86,26 @@ pub fn verify_jwt_token(token: &str) -> Result<Claims, AppError> {
     Ok(token_data.claims)
 }
 
+/// Verify a JWT and resolve the user it was issued to.
+pub fn authenticate_token(token: &str) -> Result<AuthenticatedUser, AppError> {
+    let claims = verify_jwt_token(token)?;
+    
+    // Check if token is expired
+    let now = Utc::now().timestamp();
+    if claims.exp < now {
+        return Err(AppError::AuthenticationError("Token expired".to_string()));
+    }
+
+    Ok(AuthenticatedUser {
+        user_id: Uuid::parse_str(&claims.sub)
+            .map_err(|_| AppError::AuthenticationError("Invalid user ID in token".to_string()))?,
+        email: claims.email,
+        role_id: claims.role_id,
+        role_name: claims.role_name,
+        scope: claims.scope,
+    })
+}
+
 pub async fn auth_middleware(
     mut request: Request,
     next: Next,
@@ -76,33 +114,18 @@ pub async fn auth_middleware(
         .headers()
         .get(header::AUTHORIZATION)
         .and_then(|auth_header| auth_header.to_str().ok())
-        .and_then(|auth_str| {
-            if auth_str.starts_with("Bearer ") {
-                Some(auth_str[7..].to_string())
-            } else {
-                None
-            }
-        });
+        .and_then(|auth_str| auth_str.strip_prefix("Bearer ").map(|token| token.to_string()));
 
     let token = auth_header.ok_or_else(|| {
         AppError::AuthenticationError("Missing authorization header".to_string())
     })?;
 
-    let claims = verify_jwt_token(&token)?;
-    
-    // Check if token is expired
-    let now = Utc::now().timestamp();
-    if claims.exp < now {
-        return Err(AppError::AuthenticationError("Token expired".to_string()));
-    }
+    let authenticated_user = authenticate_token(&token)?;
 
-    let authenticated_user = AuthenticatedUser {
-        user_id: Uuid::parse_str(&claims.sub)
-            .map_err(|_| AppError::AuthenticationError("Invalid user ID in token".to_string()))?,
-        email: claims.email,
-        role_id: claims.role_id,
-        role_name: claims.role_name,
-    };
+    // Read-only tokens may look, but not change anything
+    if authenticated_user.is_read_only() && !request.method().is_safe() {
+        return Err(AppError::AuthorizationError("Read-only tokens can't make changes".to_string()));
+    }
 
     // Insert the authenticated user into the request extensions
     request.extensions_mut().insert(authenticated_user);
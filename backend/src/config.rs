@@ -1,6 +1,7 @@
 use config::{Config, Environment, File};
 use serde::Deserialize;
 use std::env;
+use std::time::Duration;
 use tracing::warn;
 
 #[derive(Debug, Deserialize, Clone)]
@@ -8,12 +9,32 @@ pub struct AppConfig {
     pub server: ServerConfig,
     pub database: DatabaseConfig,
     pub cors: CorsConfig,
+    #[serde(default)]
+    pub compaction: CompactionConfig,
+    #[serde(default)]
+    pub websocket: WebSocketConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub persistence: PersistenceConfig,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    /// On SIGTERM/SIGINT, how long to wait for connections to drain and
+    /// pending changes to be written before exiting anyway
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+}
+
+fn default_shutdown_timeout_secs() -> u64 { 30 }
+
+impl ServerConfig {
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -45,12 +66,203 @@ pub struct CorsConfig {
     pub allowed_methods: Vec<String>,
 }
 
+/// When the background job folds a document's CRDT update log into its snapshot.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CompactionConfig {
+    #[serde(default = "default_compaction_interval_secs")]
+    pub interval_secs: u64,
+    /// Compact once a document has logged at least this many updates
+    #[serde(default = "default_compaction_max_updates")]
+    pub max_updates: i64,
+    /// or once its logged updates take up at least this many bytes
+    #[serde(default = "default_compaction_max_bytes")]
+    pub max_bytes: i64,
+}
+
+fn default_compaction_interval_secs() -> u64 { 300 }
+fn default_compaction_max_updates() -> i64 { 1000 }
+fn default_compaction_max_bytes() -> i64 { 1024 * 1024 }
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_compaction_interval_secs(),
+            max_updates: default_compaction_max_updates(),
+            max_bytes: default_compaction_max_bytes(),
+        }
+    }
+}
+
+/// How many documents are kept loaded in memory before idle ones are evicted.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CacheConfig {
+    #[serde(default = "default_cache_max_documents")]
+    pub max_documents: usize,
+    /// Budget for the loaded documents' CRDT state, estimated from its encoded size
+    #[serde(default = "default_cache_max_bytes")]
+    pub max_bytes: usize,
+}
+
+fn default_cache_max_documents() -> usize { 1000 }
+fn default_cache_max_bytes() -> usize { 256 * 1024 * 1024 }
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_documents: default_cache_max_documents(),
+            max_bytes: default_cache_max_bytes(),
+        }
+    }
+}
+
+/// When changes to loaded documents are written to Postgres.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PersistenceConfig {
+    /// Changes to a document are collected for this long, then written in one
+    /// transaction; 0 writes every change right away
+    #[serde(default = "default_flush_window_ms")]
+    pub flush_window_ms: u64,
+    /// Flush early once this many changes are waiting
+    #[serde(default = "default_max_unflushed_updates")]
+    pub max_unflushed_updates: usize,
+}
+
+fn default_flush_window_ms() -> u64 { 500 }
+fn default_max_unflushed_updates() -> usize { 100 }
+
+impl PersistenceConfig {
+    pub fn flush_window(&self) -> Duration {
+        Duration::from_millis(self.flush_window_ms)
+    }
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        Self {
+            flush_window_ms: default_flush_window_ms(),
+            max_unflushed_updates: default_max_unflushed_updates(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebSocketConfig {
+    /// Accept connections that don't present a JWT; they get a random user ID
+    #[serde(default = "default_allow_anonymous")]
+    pub allow_anonymous: bool,
+    /// How often the server pings each connection
+    #[serde(default = "default_ping_interval_secs")]
+    pub ping_interval_secs: u64,
+    /// Connections that send nothing, not even a pong, for this long are evicted
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    /// Messages a document room buffers for its slowest connection; one that
+    /// falls further behind is resynced with the full document state
+    #[serde(default = "default_channel_capacity")]
+    pub channel_capacity: usize,
+    /// Longest a long-poll sync request is held waiting for changes
+    #[serde(default = "default_poll_timeout_secs")]
+    pub poll_timeout_secs: u64,
+    /// Frames larger than this close the connection
+    #[serde(default = "default_max_frame_bytes")]
+    pub max_frame_bytes: usize,
+    /// Messages each connection may send a second, in bursts of up to
+    /// `message_burst`; 0 turns the limit off
+    #[serde(default = "default_messages_per_sec")]
+    pub messages_per_sec: u32,
+    #[serde(default = "default_message_burst")]
+    pub message_burst: u32,
+    /// Same, for all connections to a document room together
+    #[serde(default = "default_room_messages_per_sec")]
+    pub room_messages_per_sec: u32,
+    #[serde(default = "default_room_message_burst")]
+    pub room_message_burst: u32,
+    /// A connection this far over its own limit is disconnected; each message
+    /// it gets through makes up for one that was throttled
+    #[serde(default = "default_max_throttled_messages")]
+    pub max_throttled_messages: u32,
+    /// Connections this instance takes in all; 0 means no cap
+    #[serde(default = "default_max_total_connections")]
+    pub max_total_connections: usize,
+    /// Same, per document
+    #[serde(default = "default_max_connections_per_document")]
+    pub max_connections_per_document: usize,
+    /// Same, per user
+    #[serde(default = "default_max_connections_per_user")]
+    pub max_connections_per_user: usize,
+    /// How room messages reach connections on other instances
+    #[serde(default)]
+    pub relay: RelayBackend,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayBackend {
+    /// Single node: rooms only span this process
+    #[default]
+    Memory,
+    /// Relay through Postgres `LISTEN/NOTIFY`, for several replicas sharing a database
+    Postgres,
+}
+
+fn default_allow_anonymous() -> bool { true }
+fn default_ping_interval_secs() -> u64 { 30 }
+fn default_idle_timeout_secs() -> u64 { 90 }
+fn default_channel_capacity() -> usize { 100 }
+fn default_poll_timeout_secs() -> u64 { 30 }
+fn default_max_frame_bytes() -> usize { 1024 * 1024 }
+fn default_messages_per_sec() -> u32 { 50 }
+fn default_message_burst() -> u32 { 100 }
+fn default_room_messages_per_sec() -> u32 { 500 }
+fn default_room_message_burst() -> u32 { 1000 }
+fn default_max_throttled_messages() -> u32 { 50 }
+fn default_max_total_connections() -> usize { 10_000 }
+fn default_max_connections_per_document() -> usize { 1000 }
+fn default_max_connections_per_user() -> usize { 50 }
+
+impl WebSocketConfig {
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    pub fn poll_timeout(&self) -> Duration {
+        Duration::from_secs(self.poll_timeout_secs)
+    }
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            allow_anonymous: default_allow_anonymous(),
+            ping_interval_secs: default_ping_interval_secs(),
+            idle_timeout_secs: default_idle_timeout_secs(),
+            channel_capacity: default_channel_capacity(),
+            poll_timeout_secs: default_poll_timeout_secs(),
+            max_frame_bytes: default_max_frame_bytes(),
+            messages_per_sec: default_messages_per_sec(),
+            message_burst: default_message_burst(),
+            room_messages_per_sec: default_room_messages_per_sec(),
+            room_message_burst: default_room_message_burst(),
+            max_throttled_messages: default_max_throttled_messages(),
+            max_total_connections: default_max_total_connections(),
+            max_connections_per_document: default_max_connections_per_document(),
+            max_connections_per_user: default_max_connections_per_user(),
+            relay: RelayBackend::default(),
+        }
+    }
+}
+
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
             server: ServerConfig {
                 host: "0.0.0.0".to_string(),
                 port: 3000,
+                shutdown_timeout_secs: default_shutdown_timeout_secs(),
             },
             database: DatabaseConfig {
                 url: None,
@@ -66,6 +278,10 @@ impl Default for AppConfig {
                 allowed_origins: vec!["http://localhost:5173".to_string()],
                 allowed_methods: vec!["GET".to_string(), "POST".to_string(), "PUT".to_string()],
             },
+            compaction: CompactionConfig::default(),
+            websocket: WebSocketConfig::default(),
+            cache: CacheConfig::default(),
+            persistence: PersistenceConfig::default(),
         }
     }
 }
@@ -79,6 +295,7 @@ impl AppConfig {
             // Start with default values
             .set_default("server.host", "0.0.0.0")?
             .set_default("server.port", 3000)?
+            .set_default("server.shutdown_timeout_secs", default_shutdown_timeout_secs())?
             .set_default("database.host", "localhost")?
             .set_default("database.port", 5432)?
             .set_default("database.username", "postgres")?
@@ -88,6 +305,28 @@ impl AppConfig {
             .set_default("database.min_connections", 2)?
             .set_default("cors.allowed_origins", vec!["http://localhost:5173"])?
             .set_default("cors.allowed_methods", vec!["GET", "POST", "PUT"])?
+            .set_default("compaction.interval_secs", default_compaction_interval_secs())?
+            .set_default("compaction.max_updates", default_compaction_max_updates())?
+            .set_default("compaction.max_bytes", default_compaction_max_bytes())?
+            .set_default("websocket.allow_anonymous", default_allow_anonymous())?
+            .set_default("websocket.ping_interval_secs", default_ping_interval_secs())?
+            .set_default("websocket.idle_timeout_secs", default_idle_timeout_secs())?
+            .set_default("websocket.channel_capacity", default_channel_capacity() as u64)?
+            .set_default("websocket.poll_timeout_secs", default_poll_timeout_secs())?
+            .set_default("websocket.max_frame_bytes", default_max_frame_bytes() as u64)?
+            .set_default("websocket.messages_per_sec", default_messages_per_sec())?
+            .set_default("websocket.message_burst", default_message_burst())?
+            .set_default("websocket.room_messages_per_sec", default_room_messages_per_sec())?
+            .set_default("websocket.room_message_burst", default_room_message_burst())?
+            .set_default("websocket.max_throttled_messages", default_max_throttled_messages())?
+            .set_default("websocket.max_total_connections", default_max_total_connections() as u64)?
+            .set_default("websocket.max_connections_per_document", default_max_connections_per_document() as u64)?
+            .set_default("websocket.max_connections_per_user", default_max_connections_per_user() as u64)?
+            .set_default("websocket.relay", "memory")?
+            .set_default("cache.max_documents", default_cache_max_documents() as u64)?
+            .set_default("cache.max_bytes", default_cache_max_bytes() as u64)?
+            .set_default("persistence.flush_window_ms", default_flush_window_ms())?
+            .set_default("persistence.max_unflushed_updates", default_max_unflushed_updates() as u64)?
             // Load config files
             .add_source(File::with_name("config/default").required(false))
             .add_source(File::with_name(&format!("config/{}", run_mode)).required(false))
@@ -168,22 +407,53 @@ impl AppConfig {
         }
 
         // Validate database config
-        if let Some(port) = self.database.port {
-            if port == 0 {
-                return Err(config::ConfigError::NotFound("Database port cannot be 0".to_string()));
-            }
+        if let Some(port) = self.database.port
+            && port == 0
+        {
+            return Err(config::ConfigError::NotFound("Database port cannot be 0".to_string()));
+        }
+
+        if let Some(username) = &self.database.username
+            && username.is_empty()
+        {
+            return Err(config::ConfigError::NotFound("Database username cannot be empty".to_string()));
+        }
+
+        if let Some(database) = &self.database.database
+            && database.is_empty()
+        {
+            return Err(config::ConfigError::NotFound("Database name cannot be empty".to_string()));
+        }
+
+        if self.compaction.interval_secs == 0 {
+            return Err(config::ConfigError::NotFound("Compaction interval cannot be 0".to_string()));
+        }
+
+        // A live client answers every ping, so it must get at least one before timing out
+        if self.websocket.ping_interval_secs == 0 || self.websocket.idle_timeout_secs <= self.websocket.ping_interval_secs {
+            return Err(config::ConfigError::NotFound(
+                "WebSocket idle timeout must be longer than a non-zero ping interval".to_string(),
+            ));
+        }
+
+        if self.websocket.channel_capacity == 0 {
+            return Err(config::ConfigError::NotFound("WebSocket channel capacity cannot be 0".to_string()));
+        }
+
+        if self.cache.max_documents == 0 {
+            return Err(config::ConfigError::NotFound("Document cache must hold at least one document".to_string()));
         }
 
-        if let Some(username) = &self.database.username {
-            if username.is_empty() {
-                return Err(config::ConfigError::NotFound("Database username cannot be empty".to_string()));
-            }
+        if self.persistence.max_unflushed_updates == 0 {
+            return Err(config::ConfigError::NotFound("persistence.max_unflushed_updates cannot be 0".to_string()));
         }
 
-        if let Some(database) = &self.database.database {
-            if database.is_empty() {
-                return Err(config::ConfigError::NotFound("Database name cannot be empty".to_string()));
-            }
+        // A replica loading a document only sees what the others have written,
+        // and relayed changes aren't merged into documents loaded after them
+        if self.websocket.relay == RelayBackend::Postgres && self.persistence.flush_window_ms > 0 {
+            return Err(config::ConfigError::NotFound(
+                "websocket.relay = \"postgres\" requires persistence.flush_window_ms = 0".to_string(),
+            ));
         }
 
         // Validate CORS config
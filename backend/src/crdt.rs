@@ -1,12 +1,41 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
+use yrs::{Doc, GetString, OffsetKind, Options, ReadTxn, StateVector, Text, TextRef, Transact, TransactionMut, UndoManager, Update};
+use yrs::undo::Options as UndoOptions;
+use yrs::updates::decoder::Decode;
 
-#[derive(Debug, Clone)]
+/// Name of the shared `Text` type holding a document's content.
+/// Yjs clients must use the same name (`ydoc.getText("content")`).
+pub const TEXT_NAME: &str = "content";
+
+/// How many past versions `get_diff` can compute a delta from. Clients that
+/// are further behind get the full state instead.
+const VERSION_HISTORY_LEN: usize = 1000;
+
+/// How many users' undo history a document keeps. Each of them observes every
+/// transaction, so beyond this the least recently active user's is dropped.
+const MAX_UNDO_USERS: usize = 32;
+
+#[derive(Debug)]
 pub struct CRDTDocument {
     pub id: String,
-    content: String,
+    doc: Doc,
+    text: TextRef,
     version: u64,
+    last_modified: i64,
+    /// State vector of the document as of each recent version, oldest first
+    history: VecDeque<(u64, StateVector)>,
+    /// Undo/redo stacks of recently active users, tracking only transactions they originated
+    undo_managers: HashMap<String, UndoTracker>,
+    /// Bumped whenever a user's undo history is used, to order them by recency
+    undo_clock: u64,
+}
+
+#[derive(Debug)]
+struct UndoTracker {
+    manager: UndoManager,
+    last_used: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
@@ -14,6 +43,35 @@ pub struct DocumentUpdate {
     pub content: String,
     pub user_id: String,
     pub timestamp: i64,
+    /// Yrs update (lib0 v1 encoding) describing this change. When present it is
+    /// applied as-is so concurrent edits merge; otherwise `content` is diffed
+    /// against the current text.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub update: Vec<u8>,
+    /// Document version this change resulted in; set by the server, and
+    /// usable as `DiffRequest.since_version`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    /// Where the change was logged, once it has been. Only used between
+    /// instances, so they can tell whether they missed anything before it.
+    #[serde(skip)]
+    pub logged_as: Option<LogPosition>,
+}
+
+/// Where an update sits in its document's update log: the `document_updates`
+/// id it was logged under, and the id of the update logged right before it
+/// (updates compaction folded into the snapshot included).
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct LogPosition {
+    pub previous: i64,
+    pub id: i64,
+}
+
+impl DocumentUpdate {
+    /// Whether this changes nothing, e.g. a save of unchanged content.
+    pub fn is_empty(&self) -> bool {
+        self.update.as_slice() == Update::EMPTY_V1
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
@@ -23,111 +81,413 @@ pub struct DocumentState {
     pub last_modified: i64,
 }
 
+/// What a reconnecting client already has, so only the rest is sent back.
+/// A state vector takes precedence over a version; with neither, or a
+/// version too old to remember, the full state is returned.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, ToSchema)]
+pub struct DiffRequest {
+    /// Client's yrs state vector (lib0 v1 encoding)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub state_vector: Vec<u8>,
+    /// Last `DocumentState.version` the client saw
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since_version: Option<u64>,
+}
+
+/// One round of long-poll sync: the client's own changes, and what it already
+/// has (as in `DiffRequest`). Answered with a `DocumentDiff` once there is
+/// anything newer, or an empty one when the wait times out.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, ToSchema)]
+pub struct SyncRequest {
+    /// Client's yrs state vector (lib0 v1 encoding), not counting `updates`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub state_vector: Vec<u8>,
+    /// Last document version the client saw
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since_version: Option<u64>,
+    /// Yrs updates (lib0 v1 encoding) the client made since, applied first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub updates: Vec<Vec<u8>>,
+    /// How long to wait for changes, at most the server's `poll_timeout_secs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
+
+/// Outcome of an undo or redo on behalf of one user.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct UndoResult {
+    /// The change that was made, or `None` if there was nothing to undo/redo
+    pub update: Option<DocumentUpdate>,
+    pub can_undo: bool,
+    pub can_redo: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct DocumentDiff {
+    /// Yrs update (lib0 v1 encoding) with everything the client is missing
+    pub update: Vec<u8>,
+    pub version: u64,
+    pub last_modified: i64,
+}
+
 impl CRDTDocument {
     pub fn new(id: String) -> Self {
-        Self { 
-            id, 
-            content: String::new(),
+        // Yjs indexes text by UTF-16 code units, so we do the same to stay compatible
+        let doc = Doc::with_options(Options {
+            offset_kind: OffsetKind::Utf16,
+            ..Options::default()
+        });
+        let text = doc.get_or_insert_text(TEXT_NAME);
+
+        let mut doc = Self {
+            id,
+            doc,
+            text,
             version: 0,
-        }
+            last_modified: chrono::Utc::now().timestamp(),
+            history: VecDeque::new(),
+            undo_managers: HashMap::new(),
+            undo_clock: 0,
+        };
+        doc.record_version();
+        doc
     }
 
     pub fn from_existing(id: String, content: String) -> Self {
-        Self { 
-            id, 
-            content,
-            version: 0,
+        let mut doc = Self::new(id);
+        if !content.is_empty() {
+            {
+                let mut txn = doc.doc.transact_mut();
+                doc.text.insert(&mut txn, 0, &content);
+            }
+            doc.history.clear();
+            doc.record_version();
+        }
+        doc
+    }
+
+    /// Rebuild a document from persisted yrs updates (a snapshot followed by
+    /// the updates logged after it), picking up at the given version.
+    pub fn restore<I, B>(id: String, updates: I, version: u64, last_modified: i64) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = B>,
+        B: AsRef<[u8]>,
+    {
+        let mut doc = Self::new(id);
+        {
+            let mut txn = doc.doc.transact_mut();
+            for update in updates {
+                let decoded = Update::decode_v1(update.as_ref())
+                    .map_err(|e| format!("Invalid CRDT update: {}", e))?;
+                txn.apply_update(decoded)
+                    .map_err(|e| format!("Failed to apply CRDT update: {}", e))?;
+            }
         }
+        doc.version = version;
+        doc.last_modified = last_modified;
+        // Nothing is known about earlier versions any more
+        doc.history.clear();
+        doc.record_version();
+        Ok(doc)
     }
 
     pub fn get_content(&self) -> String {
-        self.content.clone()
+        let txn = self.doc.transact();
+        self.text.get_string(&txn)
     }
 
+    /// Replace the whole content with `new_content`.
+    ///
+    /// Only the changed span is written to the shared text, so the resulting
+    /// update merges with concurrent edits to other parts of the document.
     pub fn update_content(&mut self, new_content: &str, user_id: &str) -> DocumentUpdate {
-        self.content = new_content.to_string();
-        self.version += 1;
-        
+        self.replace_content(new_content, user_id, true)
+    }
+
+    fn replace_content(&mut self, new_content: &str, user_id: &str, undoable: bool) -> DocumentUpdate {
+        let current = self.get_content();
+        let (index, removed, inserted) = text_splice(&current, new_content);
+        if removed == 0 && inserted.is_empty() {
+            return self.unchanged(user_id);
+        }
+
+        if undoable {
+            self.track_user(user_id);
+        }
+        let update = {
+            let mut txn = self.transact_as(user_id, undoable);
+            if removed > 0 {
+                self.text.remove_range(&mut txn, index, removed);
+            }
+            if !inserted.is_empty() {
+                self.text.insert(&mut txn, index, inserted);
+            }
+            txn.encode_update_v1()
+        };
+        self.touch();
+
         DocumentUpdate {
-            content: new_content.to_string(),
+            content: self.get_content(),
             user_id: user_id.to_string(),
-            timestamp: chrono::Utc::now().timestamp(),
+            timestamp: self.last_modified,
+            update,
+            version: Some(self.version),
+            logged_as: None,
         }
     }
 
-    pub fn apply_update(&mut self, update: &DocumentUpdate) {
-        self.content = update.content.clone();
-        self.version += 1;
+    /// Apply an update from a client and return it as it should be relayed
+    /// to others: with both the resulting content and the binary update.
+    pub fn apply_update(&mut self, update: &DocumentUpdate) -> Result<DocumentUpdate, String> {
+        self.integrate(update, true)
     }
 
-    pub fn get_state(&self) -> DocumentState {
-        DocumentState {
-            content: self.content.clone(),
-            version: self.version,
-            last_modified: chrono::Utc::now().timestamp(),
+    /// Like `apply_update`, but the change isn't recorded in its author's
+    /// undo history: for updates made elsewhere, or by someone who has no
+    /// way to ask for an undo here.
+    pub fn apply_untracked_update(&mut self, update: &DocumentUpdate) -> Result<DocumentUpdate, String> {
+        self.integrate(update, false)
+    }
+
+    fn integrate(&mut self, update: &DocumentUpdate, undoable: bool) -> Result<DocumentUpdate, String> {
+        if update.update.is_empty() {
+            // Plain-text clients only send the full content
+            return Ok(self.replace_content(&update.content, &update.user_id, undoable));
+        }
+        if update.is_empty() {
+            return Ok(self.unchanged(&update.user_id));
         }
+
+        let decoded = Update::decode_v1(&update.update)
+            .map_err(|e| format!("Invalid CRDT update: {}", e))?;
+        if undoable {
+            self.track_user(&update.user_id);
+        }
+        {
+            let mut txn = self.transact_as(&update.user_id, undoable);
+            txn.apply_update(decoded)
+                .map_err(|e| format!("Failed to apply CRDT update: {}", e))?;
+        }
+        self.touch();
+
+        Ok(DocumentUpdate {
+            content: self.get_content(),
+            user_id: update.user_id.clone(),
+            timestamp: self.last_modified,
+            update: update.update.clone(),
+            version: Some(self.version),
+            logged_as: None,
+        })
     }
 
-    pub fn merge_update(&mut self, update: &DocumentUpdate) -> Result<(), String> {
-        // Simple last-write-wins for now
-        self.apply_update(update);
-        Ok(())
+    /// An edit that changed nothing. It has no version of its own, so it
+    /// carries the current one.
+    fn unchanged(&self, user_id: &str) -> DocumentUpdate {
+        DocumentUpdate {
+            content: self.get_content(),
+            user_id: user_id.to_string(),
+            timestamp: self.last_modified,
+            update: Update::EMPTY_V1.to_vec(),
+            version: Some(self.version),
+            logged_as: None,
+        }
     }
 
-    pub fn get_diff(&self, _since_version: u64) -> Option<String> {
-        // This would return the diff since the given version
-        // For now, we'll return the full content
-        Some(self.get_content())
+    /// Revert the latest edit `user_id` made that isn't undone yet, leaving
+    /// everyone else's edits in place.
+    pub fn undo(&mut self, user_id: &str) -> UndoResult {
+        self.undo_or_redo(user_id, |manager| manager.undo_blocking())
     }
-}
 
-// Document manager to handle multiple documents
-#[derive(Debug)]
-pub struct DocumentManager {
-    documents: HashMap<String, CRDTDocument>,
-}
+    /// Re-apply the latest edit of `user_id` that was undone.
+    pub fn redo(&mut self, user_id: &str) -> UndoResult {
+        self.undo_or_redo(user_id, |manager| manager.redo_blocking())
+    }
 
-impl DocumentManager {
-    pub fn new() -> Self {
-        Self {
-            documents: HashMap::new(),
+    fn undo_or_redo(&mut self, user_id: &str, action: impl FnOnce(&mut UndoManager) -> bool) -> UndoResult {
+        let before = self.state_vector();
+        self.undo_clock += 1;
+        let Some(tracker) = self.undo_managers.get_mut(user_id) else {
+            return UndoResult { update: None, can_undo: false, can_redo: false };
+        };
+        tracker.last_used = self.undo_clock;
+        let manager = &mut tracker.manager;
+        let changed = action(manager);
+        let (can_undo, can_redo) = (manager.can_undo(), manager.can_redo());
+
+        let update = changed.then(|| {
+            self.touch();
+            DocumentUpdate {
+                content: self.get_content(),
+                user_id: user_id.to_string(),
+                timestamp: self.last_modified,
+                update: self.encode_diff(&before),
+                version: Some(self.version),
+                logged_as: None,
+            }
+        });
+        UndoResult { update, can_undo, can_redo }
+    }
+
+    /// A transaction on behalf of `user_id`. Only undoable ones carry the
+    /// user as their origin, so an edit made elsewhere under the same ID
+    /// doesn't end up in the undo history kept here.
+    fn transact_as(&self, user_id: &str, undoable: bool) -> TransactionMut<'_> {
+        if undoable {
+            self.doc.transact_mut_with(user_id)
+        } else {
+            self.doc.transact_mut()
         }
     }
 
-    pub fn create_document(&mut self, id: String) -> &CRDTDocument {
-        let doc = CRDTDocument::new(id.clone());
-        self.documents.insert(id.clone(), doc);
-        self.documents.get(&id).unwrap()
+    /// Start recording `user_id`'s transactions for undo, if we aren't yet,
+    /// making room by forgetting the least recently active user's history.
+    fn track_user(&mut self, user_id: &str) {
+        self.undo_clock += 1;
+        if let Some(tracker) = self.undo_managers.get_mut(user_id) {
+            tracker.last_used = self.undo_clock;
+            return;
+        }
+        if self.undo_managers.len() >= MAX_UNDO_USERS {
+            let oldest = self.undo_managers
+                .iter()
+                .min_by_key(|(_, tracker)| tracker.last_used)
+                .map(|(user_id, _)| user_id.clone());
+            if let Some(oldest) = oldest {
+                self.undo_managers.remove(&oldest);
+            }
+        }
+        let mut manager = UndoManager::with_scope_and_options(&self.doc, &self.text, UndoOptions::default());
+        manager.include_origin(user_id);
+        self.undo_managers.insert(user_id.to_string(), UndoTracker { manager, last_used: self.undo_clock });
     }
 
-    pub fn get_document(&self, id: &str) -> Option<&CRDTDocument> {
-        self.documents.get(id)
+    pub fn version(&self) -> u64 {
+        self.version
     }
 
-    pub fn get_document_mut(&mut self, id: &str) -> Option<&mut CRDTDocument> {
-        self.documents.get_mut(id)
+    pub fn get_state(&self) -> DocumentState {
+        DocumentState {
+            content: self.get_content(),
+            version: self.version,
+            last_modified: self.last_modified,
+        }
     }
 
-    pub fn update_document(&mut self, id: &str, content: &str, user_id: &str) -> Result<DocumentUpdate, String> {
-        if let Some(doc) = self.documents.get_mut(id) {
-            Ok(doc.update_content(content, user_id))
-        } else {
-            Err("Document not found".to_string())
+    /// Encode the full document as a single yrs update (lib0 v1).
+    pub fn encode_state_as_update(&self) -> Vec<u8> {
+        self.encode_diff(&StateVector::default())
+    }
+
+    /// Encode everything a replica at `state_vector` is missing (lib0 v1).
+    pub fn encode_diff(&self, state_vector: &StateVector) -> Vec<u8> {
+        let txn = self.doc.transact();
+        txn.encode_state_as_update_v1(state_vector)
+    }
+
+    pub fn state_vector(&self) -> StateVector {
+        let txn = self.doc.transact();
+        txn.state_vector()
+    }
+
+    pub fn merge_update(&mut self, update: &DocumentUpdate) -> Result<(), String> {
+        self.apply_untracked_update(update).map(|_| ())
+    }
+
+    /// Merge updates read back from the update log, some of which may be in
+    /// the document already. Returns what was new to it as one update, or
+    /// `None` if nothing was.
+    pub fn catch_up(&mut self, updates: impl IntoIterator<Item = Vec<u8>>) -> Result<Option<DocumentUpdate>, String> {
+        // Deletions don't advance the state vector, so the content is compared too
+        let before = (self.state_vector(), self.get_content());
+        {
+            let mut txn = self.doc.transact_mut();
+            for data in updates {
+                let decoded = Update::decode_v1(&data)
+                    .map_err(|e| format!("Invalid CRDT update: {}", e))?;
+                txn.apply_update(decoded)
+                    .map_err(|e| format!("Failed to apply CRDT update: {}", e))?;
+            }
         }
+        if (self.state_vector(), self.get_content()) == before {
+            return Ok(None);
+        }
+        self.touch();
+
+        Ok(Some(DocumentUpdate {
+            content: self.get_content(),
+            user_id: String::new(),
+            timestamp: self.last_modified,
+            update: self.encode_diff(&before.0),
+            version: Some(self.version),
+            logged_as: None,
+        }))
     }
 
-    pub fn apply_update(&mut self, id: &str, update: &DocumentUpdate) -> Result<(), String> {
-        if let Some(doc) = self.documents.get_mut(id) {
-            doc.apply_update(update);
-            Ok(())
+    /// Encode the updates made since `since_version` (lib0 v1), or `None`
+    /// if that version is unknown or too old to be in the history.
+    pub fn get_diff(&self, since_version: u64) -> Option<Vec<u8>> {
+        self.history
+            .iter()
+            .find(|(version, _)| *version == since_version)
+            .map(|(_, state_vector)| self.encode_diff(state_vector))
+    }
+
+    /// Answer a reconnecting client with only what it is missing.
+    pub fn diff(&self, request: &DiffRequest) -> Result<DocumentDiff, String> {
+        let update = if !request.state_vector.is_empty() {
+            let state_vector = StateVector::decode_v1(&request.state_vector)
+                .map_err(|e| format!("Invalid state vector: {}", e))?;
+            self.encode_diff(&state_vector)
         } else {
-            Err("Document not found".to_string())
+            request.since_version
+                .and_then(|version| self.get_diff(version))
+                .unwrap_or_else(|| self.encode_state_as_update())
+        };
+
+        Ok(DocumentDiff {
+            update,
+            version: self.version,
+            last_modified: self.last_modified,
+        })
+    }
+
+    fn touch(&mut self) {
+        self.version += 1;
+        self.last_modified = chrono::Utc::now().timestamp();
+        self.record_version();
+    }
+
+    fn record_version(&mut self) {
+        if self.history.len() == VERSION_HISTORY_LEN {
+            self.history.pop_front();
         }
+        let state_vector = self.state_vector();
+        self.history.push_back((self.version, state_vector));
     }
 }
 
-impl Default for DocumentManager {
-    fn default() -> Self {
-        Self::new()
-    }
-} 
\ No newline at end of file
+/// Compute the single splice turning `old` into `new`: the UTF-16 index where
+/// they diverge, how many UTF-16 units to remove there and the text to insert.
+fn text_splice<'a>(old: &str, new: &'a str) -> (u32, u32, &'a str) {
+    let prefix: usize = old
+        .chars()
+        .zip(new.chars())
+        .take_while(|(a, b)| a == b)
+        .map(|(c, _)| c.len_utf8())
+        .sum();
+    let old_rest = &old[prefix..];
+    let new_rest = &new[prefix..];
+    let suffix: usize = old_rest
+        .chars()
+        .rev()
+        .zip(new_rest.chars().rev())
+        .take_while(|(a, b)| a == b)
+        .map(|(c, _)| c.len_utf8())
+        .sum();
+
+    let utf16_len = |s: &str| s.encode_utf16().count() as u32;
+    let removed = &old_rest[..old_rest.len() - suffix];
+    let inserted = &new_rest[..new_rest.len() - suffix];
+    (utf16_len(&old[..prefix]), utf16_len(removed), inserted)
+}
@@ -1,18 +1,83 @@
 use crate::{error::AppError, models::{Document, DocumentHistory, User, SignupRequest, LoginRequest}};
 use sqlx::postgres::PgPool;
 use uuid::Uuid;
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use crate::crdt::{DocumentManager, DocumentUpdate};
+use crate::actor::{CacheMetrics, DocumentActors, DocumentHandle, LoadedDocument};
+use crate::crdt::{CRDTDocument, DiffRequest, DocumentDiff, DocumentUpdate, LogPosition, UndoResult};
+use crate::config::{CacheConfig, CompactionConfig, PersistenceConfig};
+use sqlx::{PgConnection, Postgres, Transaction};
+use yrs::StateVector;
+
+/// A snapshot of a document's CRDT state is written every this many versions,
+/// so rehydrating never has to replay more than this many logged updates.
+const SNAPSHOT_INTERVAL: u64 = 100;
+
+/// Latest persisted CRDT state of a document.
+struct CrdtSnapshot {
+    state: Vec<u8>,
+    version: i64,
+    last_update_id: i64,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Where a document's update log ends.
+struct LogHead {
+    /// The latest `document_updates` id, including updates compaction folded
+    /// into the snapshot
+    id: i64,
+    /// How many updates were logged in all, which is the version a document
+    /// rehydrated from the log ends up at
+    version: u64,
+}
+
+/// Updates logged for a document after a given point, to merge into a copy
+/// of it that may have missed them.
+pub(crate) struct LogTail {
+    /// Raw updates, oldest first; the snapshot's state comes first if
+    /// compaction may have deleted some of them
+    pub(crate) updates: Vec<Vec<u8>>,
+    /// The latest `document_updates` id they cover
+    pub(crate) head: i64,
+}
+
+/// What persisting a batch of updates did.
+pub(crate) struct PersistedUpdates {
+    pub(crate) updated_at: chrono::DateTime<chrono::Utc>,
+    /// Where each update was logged, in order
+    pub(crate) positions: Vec<LogPosition>,
+    /// Updates other instances logged that the document had missed, merged
+    /// into it before writing its content
+    pub(crate) caught_up: Option<DocumentUpdate>,
+}
+
+/// What a compaction pass folded away.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionReport {
+    pub documents: u64,
+    pub updates_removed: u64,
+    pub bytes_reclaimed: i64,
+}
+
+impl std::ops::AddAssign for CompactionReport {
+    fn add_assign(&mut self, other: Self) {
+        self.documents += other.documents;
+        self.updates_removed += other.updates_removed;
+        self.bytes_reclaimed += other.bytes_reclaimed;
+    }
+}
 
 #[derive(Clone)]
 pub struct Database {
     pub pool: PgPool,
-    pub crdt_manager: Arc<RwLock<DocumentManager>>,
+    /// One actor per document loaded into memory, owning its CRDT state
+    pub documents: DocumentActors,
 }
 
 impl Database {
     pub async fn new(database_url: &str) -> Result<Self, AppError> {
+        Self::with_config(database_url, CacheConfig::default(), PersistenceConfig::default()).await
+    }
+
+    pub async fn with_config(database_url: &str, cache: CacheConfig, persistence: PersistenceConfig) -> Result<Self, AppError> {
         let pool = PgPool::connect(database_url).await?;
         
         // Run migrations
@@ -20,7 +85,7 @@ impl Database {
         
         Ok(Self { 
             pool,
-            crdt_manager: Arc::new(RwLock::new(DocumentManager::new())),
+            documents: DocumentActors::new(cache, persistence),
         })
     }
 
@@ -28,127 +93,488 @@ impl Database {
         let id = Uuid::new_v4();
         let now = chrono::Utc::now();
         
-        // Create in database
-        sqlx::query!(
-            "INSERT INTO documents (id, content, created_at, updated_at) VALUES ($1, $2, $3, $4)",
+        let doc = CRDTDocument::new(id.to_string());
+
+        // Create in database, with the initial CRDT snapshot the update log builds on
+        let mut tx = self.pool.begin().await?;
+
+        let row = sqlx::query!(
+            "INSERT INTO documents (id, content, created_at, updated_at) VALUES ($1, $2, $3, $4) RETURNING created_at, updated_at",
             id,
             "",
             now,
             now
         )
-        .execute(&self.pool)
+        .fetch_one(&mut *tx)
         .await?;
 
-        // Create in CRDT manager
-        let mut manager = self.crdt_manager.write().await;
-        manager.create_document(id.to_string());
+        Self::save_crdt_snapshot(&mut tx, id, &doc, 0, 0).await?;
+
+        tx.commit().await?;
+
+        // Start its actor right away, it's likely to be edited next
+        let loaded = LoadedDocument { uuid: id, doc, logged_through: 0, created_at: row.created_at, updated_at: row.updated_at };
+        self.documents.spawn(loaded, &self.pool);
 
         Ok(id.to_string())
     }
 
     pub async fn get_document(&self, id: &str) -> Result<Document, AppError> {
-        let uuid = Uuid::parse_str(id).map_err(|_| AppError::DocumentNotFound(id.to_string()))?;
-        
-        // Try to get from CRDT first (for real-time updates)
-        let crdt_manager = self.crdt_manager.read().await;
-        
-        if let Some(crdt_doc) = crdt_manager.get_document(id) {
-            let content = crdt_doc.get_content();
-            let now = chrono::Utc::now();
-            
-            return Ok(Document {
-                id: id.to_string(),
-                content,
-                created_at: now, // We'd need to store this in CRDT too
-                updated_at: now,
-            });
+        self.document_actor(id).await?.document().await
+    }
+
+    pub async fn update_document(&self, id: &str, content: &str, ip_address: &str) -> Result<Document, AppError> {
+        self.update_document_as(id, content, "user", ip_address)
+            .await
+            .map(|(document, _)| document)
+    }
+
+    /// Update a document on behalf of `user_id`, also returning the CRDT
+    /// update that collaborators need to apply the same change.
+    pub async fn update_document_as(&self, id: &str, content: &str, user_id: &str, ip_address: &str) -> Result<(Document, DocumentUpdate), AppError> {
+        self.document_actor(id).await?
+            .edit(content, user_id, ip_address)
+            .await
+    }
+
+    /// The actor owning a document's CRDT state, started from the database
+    /// if the document isn't loaded, e.g. because it was evicted from the
+    /// cache or last edited before the server restarted.
+    pub async fn document_actor(&self, id: &str) -> Result<DocumentHandle, AppError> {
+        if let Some(handle) = self.documents.get(id) {
+            return Ok(handle);
         }
-        
-        // Fallback to database
+
+        let loaded = self.load_crdt_document(id).await?;
+        Ok(self.documents.spawn(loaded, &self.pool))
+    }
+
+    /// Persist every loaded document's pending changes, e.g. before shutting down.
+    pub async fn flush(&self) -> Result<(), AppError> {
+        self.documents.flush().await
+    }
+
+    /// Merge into every loaded document what other instances logged and it
+    /// is missing, e.g. after relayed messages were lost.
+    pub async fn catch_up(&self) -> Result<(), AppError> {
+        self.documents.catch_up().await
+    }
+
+    pub fn cache_metrics(&self) -> CacheMetrics {
+        self.documents.metrics()
+    }
+
+    /// Rehydrate a document from its latest CRDT snapshot and the updates logged after it.
+    async fn load_crdt_document(&self, id: &str) -> Result<LoadedDocument, AppError> {
+        let uuid = Uuid::parse_str(id).map_err(|_| AppError::DocumentNotFound(id.to_string()))?;
+
         let row = sqlx::query!(
-            "SELECT id, content, created_at, updated_at FROM documents WHERE id = $1",
+            "SELECT created_at, updated_at FROM documents WHERE id = $1",
             uuid
         )
         .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| AppError::DocumentNotFound(id.to_string()))?;
+
+        let mut snapshot = self.fetch_crdt_snapshot(uuid).await?;
+        if snapshot.is_none() {
+            // Never edited through the CRDT layer: seed it from the stored content.
+            // The seed is persisted so every later load shares the same yrs history,
+            // otherwise Yjs clients would see the text duplicated after a restart.
+            let stored = sqlx::query!(
+                "SELECT content FROM documents WHERE id = $1",
+                uuid
+            )
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| AppError::DocumentNotFound(id.to_string()))?;
+
+            let seed = CRDTDocument::from_existing(id.to_string(), stored.content);
+            sqlx::query!(
+                "INSERT INTO document_snapshots (document_id, state, version, last_update_id) VALUES ($1, $2, 0, 0) ON CONFLICT (document_id) DO NOTHING",
+                uuid,
+                seed.encode_state_as_update()
+            )
+            .execute(&self.pool)
+            .await?;
+
+            // Re-read in case another instance seeded it first
+            snapshot = self.fetch_crdt_snapshot(uuid).await?;
+        }
+        let snapshot = snapshot.ok_or_else(|| AppError::DocumentNotFound(id.to_string()))?;
+
+        let updates = sqlx::query!(
+            "SELECT id, update_data, created_at FROM document_updates WHERE document_id = $1 AND id > $2 ORDER BY id ASC",
+            uuid,
+            snapshot.last_update_id
+        )
+        .fetch_all(&self.pool)
         .await?;
 
-        match row {
-            Some(row) => Ok(Document {
-                id: row.id.to_string(),
-                content: row.content,
-                created_at: row.created_at,
-                updated_at: row.updated_at,
-            }),
-            None => Err(AppError::DocumentNotFound(id.to_string())),
+        let version = snapshot.version as u64 + updates.len() as u64;
+        let last_modified = updates.last().map_or(snapshot.created_at, |row| row.created_at).timestamp();
+        let logged_through = updates.last().map_or(snapshot.last_update_id, |row| row.id);
+        let data = std::iter::once(snapshot.state).chain(updates.into_iter().map(|row| row.update_data));
+        let doc = CRDTDocument::restore(id.to_string(), data, version, last_modified)
+            .map_err(AppError::InternalError)?;
+        Ok(LoadedDocument { uuid, doc, logged_through, created_at: row.created_at, updated_at: row.updated_at })
+    }
+
+    async fn fetch_crdt_snapshot(&self, uuid: Uuid) -> Result<Option<CrdtSnapshot>, AppError> {
+        let row = sqlx::query_as!(
+            CrdtSnapshot,
+            "SELECT state, version, last_update_id, created_at FROM document_snapshots WHERE document_id = $1",
+            uuid
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row)
+    }
+
+    /// Lock the document's log until the transaction ends and return where
+    /// it ends. Every document has a snapshot by the time it is loaded, so
+    /// without one it has been deleted.
+    ///
+    /// This serializes appends with each other, across instances, and with
+    /// compaction, so an update can't commit below the point a concurrent
+    /// compaction has already folded into the snapshot.
+    async fn lock_crdt_log(tx: &mut Transaction<'_, Postgres>, uuid: Uuid) -> Result<LogHead, AppError> {
+        let row = sqlx::query!(
+            r#"SELECT last_update_id, version,
+                      (SELECT MAX(id) FROM document_updates WHERE document_id = $1) AS "latest_id",
+                      (SELECT COUNT(*) FROM document_updates
+                       WHERE document_id = $1 AND id > document_snapshots.last_update_id) AS "logged!"
+               FROM document_snapshots WHERE document_id = $1 FOR UPDATE"#,
+            uuid
+        )
+        .fetch_optional(&mut **tx)
+        .await?
+        .ok_or_else(|| AppError::DocumentNotFound(uuid.to_string()))?;
+        Ok(LogHead {
+            id: row.last_update_id.max(row.latest_id.unwrap_or(0)),
+            version: (row.version + row.logged) as u64,
+        })
+    }
+
+    /// Everything logged for a document after the `document_updates` id `after`.
+    pub(crate) async fn read_crdt_log(conn: &mut PgConnection, uuid: Uuid, after: i64) -> Result<LogTail, AppError> {
+        // The updates are read before the snapshot, so any that a compaction
+        // in between deletes are in the snapshot we read
+        let rows = sqlx::query!(
+            "SELECT id, update_data FROM document_updates WHERE document_id = $1 AND id > $2 ORDER BY id ASC",
+            uuid,
+            after
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        let snapshot_id = sqlx::query!(
+            "SELECT last_update_id FROM document_snapshots WHERE document_id = $1",
+            uuid
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .map_or(0, |row| row.last_update_id);
+
+        // Compaction may have deleted updates past `after`, which only the snapshot still has
+        let mut updates = Vec::new();
+        if snapshot_id > after {
+            let row = sqlx::query!("SELECT state FROM document_snapshots WHERE document_id = $1", uuid)
+                .fetch_one(&mut *conn)
+                .await?;
+            updates.push(row.state);
         }
+        let head = rows.last().map_or(after, |row| row.id).max(snapshot_id);
+        updates.extend(rows.into_iter().map(|row| row.update_data));
+        Ok(LogTail { updates, head })
     }
 
-    pub async fn update_document(&self, id: &str, content: &str, ip_address: &str) -> Result<Document, AppError> {
+    /// Append applied updates to the document's log in the order they were
+    /// applied, writing a new snapshot every `SNAPSHOT_INTERVAL` versions.
+    /// `doc` is the state after the last of them, and `head` where the log
+    /// ended before them, which `lock_crdt_log` must be holding.
+    async fn append_crdt_updates(
+        tx: &mut Transaction<'_, Postgres>,
+        uuid: Uuid,
+        doc: &CRDTDocument,
+        updates: &[DocumentUpdate],
+        head: LogHead,
+    ) -> Result<Vec<LogPosition>, AppError> {
+        let data: Vec<Vec<u8>> = updates.iter().map(|update| update.update.clone()).collect();
+        let user_ids: Vec<String> = updates.iter().map(|update| update.user_id.clone()).collect();
+        let rows = sqlx::query!(
+            "INSERT INTO document_updates (document_id, update_data, user_id)
+             SELECT $1, data, user_id FROM UNNEST($2::bytea[], $3::text[]) WITH ORDINALITY AS u(data, user_id, n)
+             ORDER BY n
+             RETURNING id",
+            uuid,
+            &data,
+            &user_ids
+        )
+        .fetch_all(&mut **tx)
+        .await?;
+
+        // Counted from the log rather than taken from `doc`, whose version
+        // also went up for what it merged, so loading agrees with it
+        let version = head.version + rows.len() as u64;
+        if let Some(last_id) = rows.iter().map(|row| row.id).max()
+            && version / SNAPSHOT_INTERVAL > head.version / SNAPSHOT_INTERVAL
+        {
+            Self::save_crdt_snapshot(tx, uuid, doc, version, last_id).await?;
+        }
+
+        let positions = rows.iter()
+            .scan(head.id, |previous, row| {
+                let position = LogPosition { previous: *previous, id: row.id };
+                *previous = row.id;
+                Some(position)
+            })
+            .collect();
+        Ok(positions)
+    }
+
+    async fn save_crdt_snapshot(
+        tx: &mut Transaction<'_, Postgres>,
+        uuid: Uuid,
+        doc: &CRDTDocument,
+        version: u64,
+        last_update_id: i64,
+    ) -> Result<(), AppError> {
+        sqlx::query!(
+            "INSERT INTO document_snapshots (document_id, state, version, last_update_id, created_at)
+             VALUES ($1, $2, $3, $4, NOW())
+             ON CONFLICT (document_id) DO UPDATE
+             SET state = EXCLUDED.state, version = EXCLUDED.version,
+                 last_update_id = EXCLUDED.last_update_id, created_at = EXCLUDED.created_at",
+            uuid,
+            doc.encode_state_as_update(),
+            version as i64,
+            last_update_id
+        )
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Run `compact_crdt_logs` every `config.interval_secs` until the runtime shuts down.
+    pub fn spawn_compaction(&self, config: CompactionConfig) -> tokio::task::JoinHandle<()> {
+        let database = self.clone();
+        tokio::spawn(async move {
+            let period = std::time::Duration::from_secs(config.interval_secs);
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                match database.compact_crdt_logs(&config).await {
+                    Ok(report) if report.documents > 0 => tracing::info!(
+                        "Compacted CRDT logs of {} documents: removed {} updates, reclaimed {} bytes",
+                        report.documents, report.updates_removed, report.bytes_reclaimed
+                    ),
+                    Ok(_) => {}
+                    Err(e) => tracing::warn!("CRDT log compaction failed: {}", e),
+                }
+            }
+        })
+    }
+
+    /// Compact every document whose update log has passed one of the configured thresholds.
+    pub async fn compact_crdt_logs(&self, config: &CompactionConfig) -> Result<CompactionReport, AppError> {
+        let candidates = sqlx::query!(
+            "SELECT document_id FROM document_updates GROUP BY document_id HAVING COUNT(*) >= $1 OR SUM(octet_length(update_data)) >= $2",
+            config.max_updates,
+            config.max_bytes
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut report = CompactionReport::default();
+        for row in candidates {
+            report += self.compact_crdt_log(row.document_id).await?;
+        }
+        Ok(report)
+    }
+
+    /// Fold a document's whole update log into its snapshot and delete the logged rows.
+    pub async fn compact_crdt_document(&self, id: &str) -> Result<CompactionReport, AppError> {
         let uuid = Uuid::parse_str(id).map_err(|_| AppError::DocumentNotFound(id.to_string()))?;
-        let now = chrono::Utc::now();
-        
-        // Update in CRDT manager
-        let mut manager = self.crdt_manager.write().await;
-        let _update = manager.update_document(id, content, "user")
-            .map_err(|e| AppError::InternalError(e))?;
-        
-        // Update in database (for persistence)
+        self.compact_crdt_log(uuid).await
+    }
+
+    async fn compact_crdt_log(&self, uuid: Uuid) -> Result<CompactionReport, AppError> {
         let mut tx = self.pool.begin().await?;
 
+        // Holding the snapshot row blocks `persist_crdt_updates` until we commit,
+        // so every update id we see below is final and none can slip in under it
+        let Some(snapshot) = sqlx::query_as!(
+            CrdtSnapshot,
+            "SELECT state, version, last_update_id, created_at FROM document_snapshots WHERE document_id = $1 FOR UPDATE",
+            uuid
+        )
+        .fetch_optional(&mut *tx)
+        .await? else {
+            return Ok(CompactionReport::default());
+        };
+
+        let updates = sqlx::query!(
+            "SELECT id, update_data, created_at FROM document_updates WHERE document_id = $1 AND id > $2 ORDER BY id ASC",
+            uuid,
+            snapshot.last_update_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let last_update_id = updates.last().map_or(snapshot.last_update_id, |row| row.id);
+        let last_modified = updates.last().map_or(snapshot.created_at, |row| row.created_at);
+        let version = snapshot.version as u64 + updates.len() as u64;
+        let old_state_len = snapshot.state.len() as i64;
+        let data = std::iter::once(snapshot.state).chain(updates.into_iter().map(|row| row.update_data));
+        let doc = CRDTDocument::restore(uuid.to_string(), data, version, last_modified.timestamp())
+            .map_err(AppError::InternalError)?;
+        let state = doc.encode_state_as_update();
+        let new_state_len = state.len() as i64;
+
         sqlx::query!(
-            "UPDATE documents SET content = $1, updated_at = $2 WHERE id = $3",
-            content,
-            now,
+            "UPDATE document_snapshots SET state = $1, version = $2, last_update_id = $3, created_at = $4 WHERE document_id = $5",
+            state,
+            version as i64,
+            last_update_id,
+            last_modified,
             uuid
         )
         .execute(&mut *tx)
         .await?;
 
-        // Add to history
-        sqlx::query(
-            "INSERT INTO document_history (document_id, content, ip_address, timestamp) VALUES ($1, $2, $3::inet, $4)"
+        // Includes updates an earlier periodic snapshot already covered
+        let removed = sqlx::query!(
+            "DELETE FROM document_updates WHERE document_id = $1 AND id <= $2 RETURNING octet_length(update_data) AS size",
+            uuid,
+            last_update_id
         )
-        .bind(uuid)
-        .bind(content)
-        .bind(ip_address)
-        .bind(now)
-        .execute(&mut *tx)
+        .fetch_all(&mut *tx)
         .await?;
 
         tx.commit().await?;
 
-        // Return the updated document directly instead of calling get_document
-        // this circumvents call to get_document, which needs to acquire a read lock on the CRDT manager
-        // and that can cause deadlocks when multiple updates are happening concurrently
-        Ok(Document {
-            id: id.to_string(),
-            content: content.to_string(),
-            created_at: now, // This should come from the database, but we'll use current time
-            updated_at: now,
+        let removed_bytes: i64 = removed.iter().map(|row| row.size.unwrap_or(0) as i64).sum();
+        Ok(CompactionReport {
+            documents: 1,
+            updates_removed: removed.len() as u64,
+            bytes_reclaimed: removed_bytes + old_state_len - new_state_len,
         })
     }
 
-    pub async fn apply_crdt_update(&self, id: &str, update: &DocumentUpdate) -> Result<(), AppError> {
-        let mut manager = self.crdt_manager.write().await;
-        manager.apply_update(id, update)
-            .map_err(|e| AppError::InternalError(e))?;
-        Ok(())
+    /// Apply a CRDT update and return it as it should be relayed to other clients.
+    /// The update is logged and the document's stored content kept in sync.
+    /// Unless `undoable`, it isn't kept in its author's undo history, for
+    /// clients that can't ask for an undo as the same user later.
+    pub async fn apply_crdt_update(&self, id: &str, update: &DocumentUpdate, undoable: bool) -> Result<DocumentUpdate, AppError> {
+        self.document_actor(id).await?.apply(update, undoable).await
     }
 
-    pub async fn get_document_crdt_state(&self, id: &str) -> Result<crate::crdt::DocumentState, AppError> {
-        let manager = self.crdt_manager.read().await;
-        if let Some(doc) = manager.get_document(id) {
-            Ok(doc.get_state())
+    /// Merge an update that another instance applied and persisted into our
+    /// copy of the document, returning our version after it. Documents that
+    /// aren't loaded will pick it up from the database when they are, and
+    /// ones that are loading catch up once they have.
+    pub async fn merge_crdt_update(&self, id: &str, update: &DocumentUpdate) -> Result<Option<u64>, AppError> {
+        match self.documents.peek(id) {
+            Some(handle) => handle.merge(update).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Undo the latest edit `user_id` made to the document, and only theirs.
+    pub async fn undo_crdt_update(&self, id: &str, user_id: &str) -> Result<UndoResult, AppError> {
+        self.document_actor(id).await?.undo(user_id).await
+    }
+
+    /// Redo the latest edit of `user_id` that was undone.
+    pub async fn redo_crdt_update(&self, id: &str, user_id: &str) -> Result<UndoResult, AppError> {
+        self.document_actor(id).await?.redo(user_id).await
+    }
+
+    /// Log updates already applied to the in-memory document, in one
+    /// transaction, and keep the document's stored content in sync, adding a
+    /// history entry if they include edits, the latest made from
+    /// `edited_from`. `doc` is the state after the updates, with everything
+    /// logged up to `logged_through` merged into it.
+    ///
+    /// Whatever other instances logged after that, e.g. because their relayed
+    /// messages were lost, is merged into `doc` first, so the content written
+    /// back is never missing their changes.
+    pub(crate) async fn persist_crdt_updates(
+        pool: &PgPool,
+        uuid: Uuid,
+        doc: &mut CRDTDocument,
+        logged_through: i64,
+        applied: &[DocumentUpdate],
+        edited_from: Option<&str>,
+    ) -> Result<PersistedUpdates, AppError> {
+        let now = chrono::Utc::now();
+        let mut tx = pool.begin().await?;
+
+        let head = Self::lock_crdt_log(&mut tx, uuid).await?;
+        let caught_up = if head.id > logged_through {
+            let missed = Self::read_crdt_log(&mut tx, uuid, logged_through).await?;
+            doc.catch_up(missed.updates).map_err(AppError::InternalError)?
         } else {
-            Err(AppError::DocumentNotFound(id.to_string()))
+            None
+        };
+        let content = doc.get_content();
+
+        let positions = Self::append_crdt_updates(&mut tx, uuid, doc, applied, head).await?;
+
+        // Postgres has the final say on `updated_at`, the table's trigger overrides it
+        let row = sqlx::query!(
+            "UPDATE documents SET content = $1, updated_at = $2 WHERE id = $3 RETURNING updated_at",
+            content,
+            now,
+            uuid
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        if let Some(ip_address) = edited_from {
+            sqlx::query(
+                "INSERT INTO document_history (document_id, content, ip_address, timestamp) VALUES ($1, $2, $3::inet, $4)"
+            )
+            .bind(uuid)
+            .bind(&content)
+            .bind(ip_address)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
         }
+
+        tx.commit().await?;
+        Ok(PersistedUpdates { updated_at: row.updated_at, positions, caught_up })
+    }
+
+    pub async fn get_document_crdt_state(&self, id: &str) -> Result<crate::crdt::DocumentState, AppError> {
+        self.document_actor(id).await?.read(|doc| doc.get_state()).await
+    }
+
+    pub async fn get_document_crdt_state_vector(&self, id: &str) -> Result<StateVector, AppError> {
+        self.document_actor(id).await?.read(|doc| doc.state_vector()).await
+    }
+
+    /// Encode the updates a replica at `state_vector` is missing.
+    pub async fn get_document_crdt_diff(&self, id: &str, state_vector: &StateVector) -> Result<Vec<u8>, AppError> {
+        let state_vector = state_vector.clone();
+        self.document_actor(id).await?.read(move |doc| doc.encode_diff(&state_vector)).await
+    }
+
+    /// Encode only what a client described by `request` is missing.
+    pub async fn get_document_crdt_delta(&self, id: &str, request: &DiffRequest) -> Result<DocumentDiff, AppError> {
+        let request = request.clone();
+        self.document_actor(id).await?
+            .read(move |doc| doc.diff(&request))
+            .await?
+            .map_err(AppError::ValidationError)
     }
 
     pub async fn get_document_history(&self, id: &str) -> Result<Vec<DocumentHistory>, AppError> {
         let uuid = Uuid::parse_str(id).map_err(|_| AppError::DocumentNotFound(id.to_string()))?;
         
-        // First check if document exists
-        let _document = self.get_document(id).await?;
+        // Also checks the document exists; its pending edits belong in the history
+        self.document_actor(id).await?.flush().await?;
 
         let rows = sqlx::query!(
             "SELECT content, ip_address::text, timestamp FROM document_history WHERE document_id = $1 ORDER BY timestamp ASC",
@@ -172,6 +598,9 @@ impl Database {
     // Additional PostgreSQL-specific methods for production features
     pub async fn get_document_stats(&self, id: &str) -> Result<(i64, chrono::DateTime<chrono::Utc>), AppError> {
         let uuid = Uuid::parse_str(id).map_err(|_| AppError::DocumentNotFound(id.to_string()))?;
+        if let Some(handle) = self.documents.peek(id) {
+            handle.flush().await?;
+        }
         
         let row = sqlx::query!(
             "SELECT COUNT(*) as history_count, MAX(timestamp) as last_updated FROM document_history WHERE document_id = $1",
@@ -37,6 +37,9 @@ pub enum AppError {
     
     #[error("User already exists: {0}")]
     UserAlreadyExists(String),
+
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
 }
 
 impl IntoResponse for AppError {
@@ -75,6 +78,9 @@ impl IntoResponse for AppError {
             AppError::UserAlreadyExists(email) => {
                 (StatusCode::CONFLICT, format!("User already exists: {}", email))
             }
+            AppError::ServiceUnavailable(msg) => {
+                (StatusCode::SERVICE_UNAVAILABLE, format!("Service unavailable: {}", msg))
+            }
         };
 
         let body = Json(json!({
@@ -86,4 +92,18 @@ impl IntoResponse for AppError {
     }
 }
 
+impl AppError {
+    /// Message safe to show to a client, without internal details.
+    /// Used where errors are reported over a socket rather than as an HTTP response.
+    pub fn public_message(&self) -> String {
+        match self {
+            AppError::DatabaseError(_) | AppError::MigrationError(_) | AppError::InternalError(_) => {
+                tracing::error!("{}", self);
+                "Internal server error".to_string()
+            }
+            other => other.to_string(),
+        }
+    }
+}
+
 pub type AppResult<T> = Result<T, AppError>; 
\ No newline at end of file
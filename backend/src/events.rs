@@ -0,0 +1,196 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::stream::{self, Stream};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::convert::Infallible;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    app::AppState,
+    crdt::DiffRequest,
+    error::{AppError, AppResult},
+    websocket::{authenticate_connection, ConnectionIdentity, RoomGuard, RoomMessage, WebSocketMessage},
+};
+
+/// Header an `EventSource` sends when it reconnects, with the ID of the last
+/// event it received.
+const LAST_EVENT_ID: &str = "last-event-id";
+
+/// Versions a stream keeps track of past a gap before it stops waiting for
+/// the gap to fill.
+const MAX_VERSIONS_AHEAD: usize = 1000;
+
+/// Stream a document room's `WebSocketMessage`s as Server-Sent Events, for
+/// clients that can't open a WebSocket and send their edits over REST.
+///
+/// Events that change the document carry the version the client is caught
+/// up to as their ID, so a client reconnecting with `Last-Event-ID` starts
+/// with a `DocumentDiff` of what it missed instead of the whole `DocumentState`.
+pub async fn document_events_handler(
+    Path(document_id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AppResult<Sse<impl Stream<Item = Result<Event, Infallible>>>> {
+    info!("Event stream requested for document: {}", document_id);
+
+    if state.ws_manager.is_shutting_down() {
+        return Err(AppError::ServiceUnavailable("Server is restarting".to_string()));
+    }
+    // EventSource can't set headers either, so it authenticates like a WebSocket
+    let ConnectionIdentity { user_id, spectator, .. } = authenticate_connection(&state, &params, &headers, None)?;
+    let since_version = headers
+        .get(LAST_EVENT_ID)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    // Join before taking the first snapshot, so nothing published after it is missed
+    let connection_id = Uuid::new_v4().to_string();
+    let rx = if spectator {
+        state.ws_manager.spectate_document(document_id.clone(), user_id.clone(), connection_id.clone()).await
+    } else {
+        state.ws_manager.join_document(document_id.clone(), user_id.clone(), connection_id.clone()).await
+    }?;
+    let membership = RoomGuard::joined(state.clone(), document_id, user_id, connection_id);
+
+    let (first, version) = match since_version {
+        Some(since_version) => {
+            let request = DiffRequest { state_vector: Vec::new(), since_version: Some(since_version) };
+            let diff = state.database.get_document_crdt_delta(&membership.document_id, &request).await?;
+            let version = diff.version;
+            (WebSocketMessage::DocumentDiff { diff }, version)
+        }
+        None => {
+            let document = state.database.get_document_crdt_state(&membership.document_id).await?;
+            let version = document.version;
+            (WebSocketMessage::DocumentState { state: document }, version)
+        }
+    };
+    let mut events = EventStream {
+        rx,
+        pending: VecDeque::from([document_event(&first, Some(version))]),
+        done: false,
+        caught_up_to: version,
+        ahead: BTreeSet::new(),
+        membership,
+    };
+    events.push_awareness().await;
+
+    let stream = stream::unfold(events, |mut events| async move {
+        loop {
+            if let Some(event) = events.pending.pop_front() {
+                return Some((Ok(event), events));
+            }
+            if events.done {
+                return None;
+            }
+            events.receive().await;
+        }
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Encode a message as an event, with `version` as its ID if given.
+fn document_event(message: &WebSocketMessage, version: Option<u64>) -> Event {
+    let event = Event::default().data(serde_json::to_string(message).unwrap_or_default());
+    match version {
+        Some(version) => event.id(version.to_string()),
+        None => event,
+    }
+}
+
+struct EventStream {
+    rx: broadcast::Receiver<RoomMessage>,
+    /// Events ready to be sent, in order
+    pending: VecDeque<Event>,
+    /// Set once nothing more is to come
+    done: bool,
+    /// The client has every update up to this version. Broadcasts can
+    /// overtake each other, so this is where the events sent so far leave
+    /// no gap, not the latest version among them.
+    caught_up_to: u64,
+    /// Versions sent past a gap after `caught_up_to`
+    ahead: BTreeSet<u64>,
+    /// Leaves the room once the stream is dropped, i.e. when the client has gone away
+    membership: RoomGuard,
+}
+
+impl EventStream {
+    /// Wait for the next room message and queue the events it makes.
+    async fn receive(&mut self) {
+        let received = tokio::select! {
+            received = self.rx.recv() => received,
+            _ = self.membership.state.ws_manager.shutdown_signal() => {
+                self.pending.push_back(document_event(&WebSocketMessage::ServerRestarting, None));
+                self.done = true;
+                return;
+            }
+        };
+
+        match received {
+            Ok(msg) => {
+                let version = match &msg.message {
+                    WebSocketMessage::DocumentState { state } => Some(self.caught_up(state.version)),
+                    WebSocketMessage::DocumentUpdated { update } => Some(self.sent(update.version)),
+                    _ => None,
+                };
+                self.pending.push_back(document_event(&msg.message, version));
+            }
+            // As on a WebSocket, replace what was dropped with the full state
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                let document_id = &self.membership.document_id;
+                warn!("Event stream {} lagged {} messages behind, resyncing", self.membership.connection_id, skipped);
+                match self.membership.state.database.get_document_crdt_state(document_id).await {
+                    Ok(document) => {
+                        let version = self.caught_up(document.version);
+                        self.pending.push_back(document_event(&WebSocketMessage::DocumentState { state: document }, Some(version)));
+                    }
+                    Err(e) => {
+                        self.pending.push_back(document_event(&WebSocketMessage::Error { message: e.public_message() }, None));
+                    }
+                }
+                self.push_awareness().await;
+            }
+            Err(broadcast::error::RecvError::Closed) => self.done = true,
+        }
+    }
+
+    /// Record that the whole document as of `version` was sent.
+    fn caught_up(&mut self, version: u64) -> u64 {
+        self.caught_up_to = self.caught_up_to.max(version);
+        self.ahead.retain(|&ahead| ahead > self.caught_up_to);
+        self.advance()
+    }
+
+    /// Record that the update which resulted in `version` was sent.
+    fn sent(&mut self, version: Option<u64>) -> u64 {
+        if let Some(version) = version.filter(|&version| version > self.caught_up_to) {
+            self.ahead.insert(version);
+        }
+        // Should the gap never fill, a resuming client simply gets more than it needs
+        if self.ahead.len() > MAX_VERSIONS_AHEAD {
+            self.ahead.clear();
+        }
+        self.advance()
+    }
+
+    fn advance(&mut self) -> u64 {
+        while self.ahead.remove(&(self.caught_up_to + 1)) {
+            self.caught_up_to += 1;
+        }
+        self.caught_up_to
+    }
+
+    /// Queue the room's awareness states, if anyone in it has any.
+    async fn push_awareness(&mut self) {
+        let states = self.membership.state.ws_manager.awareness_states(&self.membership.document_id).await;
+        if !states.is_empty() {
+            self.pending.push_back(document_event(&WebSocketMessage::Awareness { states }, None));
+        }
+    }
+}
@@ -1,18 +1,21 @@
 use axum::{
-    extract::{Path, State, Extension},
+    extract::{Path, Query, State, Extension},
     response::Json,
     http::HeaderMap,
 };
+use std::collections::HashMap;
 use validator::Validate;
 
 
 use crate::{
+    actor::CacheMetrics,
     app::AppState,
     auth::{AuthenticatedUser, require_role},
     error::{AppError, AppResult},
     models::{CreateDocumentResponse, Document, DocumentHistory, UpdateDocumentRequest, SignupRequest, LoginRequest, AuthResponse, User, UpdateUserRoleRequest},
-    crdt::{DocumentUpdate, DocumentState},
+    crdt::{DiffRequest, DocumentDiff, DocumentUpdate, DocumentState, UndoResult},
     utils::{extract_client_ip_from_headers},
+    websocket::{authenticate_connection, spectator_edit, ConnectionIdentity},
 };
 
 /// Create a new document
@@ -68,7 +71,13 @@ pub async fn update_document(
     // Extract IP address from headers (proxy headers or fallback to localhost)
     let ip_address = extract_client_ip_from_headers(&headers);
     
-    let document = state.database.update_document(&id, &payload.content, &ip_address).await?;
+    let (document, update) = state.database.update_document_as(&id, &payload.content, "user", &ip_address).await?;
+    // Live clients, e.g. those following the document's event stream, see REST
+    // edits too. The editor's periodic save repeats what it already sent live,
+    // so unchanged content isn't echoed back to it.
+    if !update.is_empty() {
+        state.ws_manager.broadcast_update(&id, update, None).await;
+    }
     Ok(Json(document))
 }
 
@@ -94,6 +103,13 @@ pub async fn get_document_stats(
     })))
 }
 
+/// Get how the in-memory document cache is doing
+pub async fn get_cache_metrics(
+    State(state): State<AppState>,
+) -> Json<CacheMetrics> {
+    Json(state.database.cache_metrics())
+}
+
 /// Search documents by content
 pub async fn search_documents(
     axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
@@ -119,19 +135,73 @@ pub async fn get_document_crdt_state(
     Ok(Json(state))
 }
 
+/// CRDT: Get only the updates a client is missing (incremental sync)
+pub async fn get_document_crdt_diff(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Json(request): Json<DiffRequest>,
+) -> AppResult<Json<DocumentDiff>> {
+    let diff = state.database.get_document_crdt_delta(&id, &request).await?;
+    Ok(Json(diff))
+}
+
 /// CRDT: Apply update from another client
 pub async fn apply_crdt_update(
     Path(id): Path<String>,
     State(state): State<AppState>,
     Json(update): Json<DocumentUpdate>,
 ) -> AppResult<Json<serde_json::Value>> {
-    state.database.apply_crdt_update(&id, &update).await?;
+    let applied = state.database.apply_crdt_update(&id, &update, true).await?;
+    if !applied.is_empty() {
+        state.ws_manager.broadcast_update(&id, applied, None).await;
+    }
     Ok(Json(serde_json::json!({
         "status": "success",
         "message": "Update applied successfully"
     })))
 }
 
+/// CRDT: Undo the requesting user's latest edit, leaving other users' edits alone
+pub async fn undo_crdt_update(
+    Path(id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AppResult<Json<UndoResult>> {
+    let user_id = undoing_user(&state, &params, &headers)?;
+    let result = state.database.undo_crdt_update(&id, &user_id).await?;
+    if let Some(update) = &result.update {
+        state.ws_manager.broadcast_update(&id, update.clone(), None).await;
+    }
+    Ok(Json(result))
+}
+
+/// CRDT: Redo the requesting user's latest undone edit
+pub async fn redo_crdt_update(
+    Path(id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> AppResult<Json<UndoResult>> {
+    let user_id = undoing_user(&state, &params, &headers)?;
+    let result = state.database.redo_crdt_update(&id, &user_id).await?;
+    if let Some(update) = &result.update {
+        state.ws_manager.broadcast_update(&id, update.clone(), None).await;
+    }
+    Ok(Json(result))
+}
+
+/// Whose edits an undo or redo request may revert: the user its token was
+/// issued to, like on a WebSocket. Anonymous requests get a fresh ID, which
+/// has nothing to undo.
+fn undoing_user(state: &AppState, params: &HashMap<String, String>, headers: &HeaderMap) -> AppResult<String> {
+    let ConnectionIdentity { user_id, spectator, .. } = authenticate_connection(state, params, headers, None)?;
+    if spectator {
+        return Err(spectator_edit());
+    }
+    Ok(user_id)
+}
+
 /// Register a new user account
 #[utoipa::path(
     post,
@@ -185,6 +255,9 @@ pub async fn login(
     payload.validate().map_err(|e| {
         AppError::ValidationError(format!("Validation failed: {}", e))
     })?;
+    if let Some(scope) = payload.scope.as_deref() && scope != crate::auth::READ_ONLY_SCOPE {
+        return Err(AppError::ValidationError(format!("Unknown token scope: {}", scope)));
+    }
 
     // Get user and password hash
     let user = state.database.authenticate_user(&payload).await?;
@@ -196,8 +269,8 @@ pub async fn login(
         return Err(AppError::AuthenticationError("Invalid password".to_string()));
     }
 
-    // Generate JWT token
-    let token = crate::auth::create_jwt_token(&user)?;
+    // Generate JWT token, limited to the requested scope
+    let token = crate::auth::create_scoped_jwt_token(&user, payload.scope.as_deref())?;
 
     Ok(Json(AuthResponse { token, user }))
 }
@@ -1,12 +1,18 @@
+pub mod actor;
 pub mod app;
 pub mod auth;
 pub mod config;
 pub mod crdt;
 pub mod database;
 pub mod error;
+pub mod events;
 pub mod handlers;
 pub mod models;
+pub mod multiplex;
 pub mod openapi;
+pub mod poll;
+pub mod rate_limit;
+pub mod relay;
 pub mod tests;
 pub mod utils;
 pub mod websocket; 
\ No newline at end of file
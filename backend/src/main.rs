@@ -1,22 +1,10 @@
-mod app;
-mod auth;
-mod config;
-mod crdt;
-mod database;
-mod error;
-mod handlers;
-mod models;
-mod openapi;
-mod tests;
-mod utils;
-mod websocket;
-
 use std::net::{SocketAddr, IpAddr, Ipv4Addr};
-use tracing::{info, Level};
+use std::future::IntoFuture;
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
-use crate::{
-    app::create_app,
+use collaborative_docs_rs::{
+    app::{create_app_with_state, AppState},
     config::AppConfig,
     database::Database,
 };
@@ -33,13 +21,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = AppConfig::load()?;
     
     // Initialize database
-    let database = Database::new(&config.database_url()).await.map_err(|e| {
+    let database = Database::with_config(&config.database_url(), config.cache.clone(), config.persistence.clone()).await.map_err(|e| {
         eprintln!("Failed to initialize database: {}", e);
         std::process::exit(1);
     })?;
+
+    // Keep CRDT update logs from growing without bound
+    database.spawn_compaction(config.compaction.clone());
     
     // Create application
-    let app = create_app(database, &config);
+    let state = AppState::new(database.clone(), &config);
+    let app = create_app_with_state(state.clone(), &config);
 
     // Parse host address
     let host_ip = if config.server.host == "0.0.0.0" {
@@ -62,13 +54,87 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     info!("  PUT    /api/doc/{{id}}");
     info!("  GET    /api/doc/{{id}}/history");
     info!("  GET    /api/doc/{{id}}/stats");
+    info!("  GET    /api/doc/{{id}}/events (Server-Sent Events)");
     info!("  GET    /api/search?q=query");
+    info!("  GET    /api/cache/metrics");
     info!("  GET    /api/doc/{{id}}/crdt/state");
+    info!("  POST   /api/doc/{{id}}/crdt/diff");
     info!("  POST   /api/doc/{{id}}/crdt/update");
+    info!("  POST   /api/doc/{{id}}/crdt/undo");
+    info!("  POST   /api/doc/{{id}}/crdt/redo");
+    info!("  POST   /api/doc/{{id}}/crdt/sync (long-poll)");
     info!("  GET    /ws/doc/{{document_id}} (WebSocket)");
+    info!("  GET    /ws/multiplex (WebSocket)");
     info!("  GET    /ws/info/{{document_id}}");
+    info!("  GET    /ws/metrics");
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let mut server = tokio::spawn(
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async {
+                let _ = stop_rx.await;
+            })
+            .into_future(),
+    );
+
+    tokio::select! {
+        _ = shutdown_signal() => {}
+        result = &mut server => return Ok(result??),
+    }
+
+    let timeout = config.server.shutdown_timeout();
+    info!("Shutting down within {:?}", timeout);
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    // Stop accepting connections and upgrades, and ask open sockets to reconnect later.
+    // Draining gets half the deadline so that pending changes still have time to be written.
+    state.ws_manager.shut_down();
+    let _ = stop_tx.send(());
+    let drained = tokio::time::timeout_at(deadline - timeout / 2, async {
+        let _ = (&mut server).await;
+        state.ws_manager.drained().await;
+    })
+    .await;
+    if drained.is_err() {
+        warn!("Connections still open after {:?}, dropping them", timeout / 2);
+    }
+
+    // Changes still waiting for their flush window would be lost otherwise
+    match tokio::time::timeout_at(deadline, database.flush()).await {
+        Ok(Ok(())) => info!("Pending document changes written"),
+        Ok(Err(e)) => error!("Failed to persist pending document changes: {}", e),
+        Err(_) => error!("Timed out persisting pending document changes"),
+    }
     Ok(())
 }
+
+/// Resolve on Ctrl+C, or on SIGTERM where there is one.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Cannot listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Cannot listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received Ctrl+C"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
@@ -70,6 +70,9 @@ pub struct LoginRequest {
     #[validate(email(message = "Invalid email format"))]
     pub email: String,
     pub password: String,
+    /// Limit the issued token, e.g. to `read` for a viewer that may only follow documents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
@@ -86,6 +89,9 @@ pub struct Claims {
     pub role_name: String,
     pub exp: i64, // expiration time
     pub iat: i64, // issued at
+    /// What the token may be used for; without one, whatever the role allows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Validate, ToSchema)]
@@ -0,0 +1,310 @@
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, HeaderValue, header},
+    response::{IntoResponse, Response},
+};
+use axum_tws::{WebSocket, WebSocketUpgrade};
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    app::AppState,
+    error::AppError,
+    rate_limit::Verdict,
+    utils::extract_client_ip_from_headers,
+    websocket::{
+        authenticate_connection, decode_json_frame, handle_json_message, offered_subprotocols,
+        error_close_frames, send_frames, ClientContext, ConnectionIdentity, Envelope, RoomMessage, WebSocketMessage, WireProtocol,
+        CLOSE_TIMEOUT, ENVELOPE_SUBPROTOCOL, TOKEN_SUBPROTOCOL_PREFIX,
+    },
+};
+
+/// A document room a multiplexed socket is subscribed to. Each subscription
+/// joins the room under its own connection ID, so the room treats it like a
+/// connection of its own.
+struct Subscription {
+    connection_id: String,
+    /// Forwards the room's messages to the socket, tagged with the document
+    forwarder: JoinHandle<()>,
+}
+
+/// Who is on the other end of a multiplexed socket.
+struct MultiplexedClient<'a> {
+    state: &'a AppState,
+    user_id: &'a str,
+    ip_address: &'a str,
+    /// Follows its documents without editing them
+    spectator: bool,
+    anonymous: bool,
+    /// Frames for the socket: replies, and room messages from the forwarders
+    outgoing: &'a mpsc::Sender<axum_tws::Message>,
+}
+
+// WebSocket handler following any number of documents over one connection
+pub async fn multiplex_handler(
+    ws: WebSocketUpgrade,
+    Query(params): Query<HashMap<String, String>>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    info!("Multiplexed WebSocket upgrade request");
+
+    if state.ws_manager.is_shutting_down() {
+        return AppError::ServiceUnavailable("Server is restarting".to_string()).into_response();
+    }
+
+    let offered_protocols = offered_subprotocols(&headers);
+    let token_protocol = offered_protocols.iter().find(|p| p.starts_with(TOKEN_SUBPROTOCOL_PREFIX));
+    let identity = match authenticate_connection(&state, &params, &headers, token_protocol) {
+        Ok(identity) => identity,
+        Err(e) => return e.into_response(),
+    };
+    let ip_address = extract_client_ip_from_headers(&headers);
+
+    // This endpoint only speaks envelopes, so the subprotocol is optional
+    let selected_protocol = if offered_protocols.iter().any(|p| p == ENVELOPE_SUBPROTOCOL) {
+        Some(HeaderValue::from_static(ENVELOPE_SUBPROTOCOL))
+    } else {
+        token_protocol.and_then(|p| HeaderValue::from_str(p).ok())
+    };
+
+    // Frames over the limit close the connection with 1009 (Message Too Big)
+    let limits = axum_tws::Limits::default().max_payload_len(Some(state.ws_manager.config().max_frame_bytes));
+    let mut response = ws.limits(limits)
+        .on_upgrade(move |socket| handle_multiplexed_socket(socket, identity, state, ip_address));
+    if let Some(selected_protocol) = selected_protocol {
+        response.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, selected_protocol);
+    }
+    response.into_response()
+}
+
+async fn handle_multiplexed_socket(socket: WebSocket, identity: ConnectionIdentity, state: AppState, ip_address: String) {
+    let ConnectionIdentity { user_id, spectator, anonymous } = identity;
+    let (sender, mut receiver) = socket.split();
+    info!("Multiplexed WebSocket connection established by user {}", user_id);
+
+    // A slow client backs this up, which makes its rooms' channels lag behind and resync it
+    let (outgoing, outgoing_rx) = mpsc::channel::<axum_tws::Message>(state.ws_manager.config().channel_capacity);
+    let ping_interval = state.ws_manager.config().ping_interval();
+    let idle_timeout = state.ws_manager.config().idle_timeout();
+
+    // Handle outgoing messages
+    let shutdown = state.ws_manager.shutdown_signal();
+    let mut send_task = tokio::spawn(send_frames(sender, outgoing_rx, WireProtocol::Envelope, ping_interval, shutdown));
+
+    // Handle incoming messages here rather than in a task of their own, so
+    // the subscriptions are still around to be cleaned up afterwards
+    let mut subscriptions = HashMap::new();
+    let client = MultiplexedClient {
+        state: &state,
+        user_id: &user_id,
+        ip_address: &ip_address,
+        spectator,
+        anonymous,
+        outgoing: &outgoing,
+    };
+    let mut limiter = state.ws_manager.connection_limiter();
+    let receive = async {
+        loop {
+            // Any frame counts as a sign of life, including pongs to our pings
+            let msg = match tokio::time::timeout(idle_timeout, receiver.next()).await {
+                Ok(Some(Ok(msg))) => msg,
+                Ok(Some(Err(e))) => {
+                    error!("WebSocket error: {}", e);
+                    break;
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    info!("Evicting multiplexed connection of user {} after {:?} without traffic", user_id, idle_timeout);
+                    break;
+                }
+            };
+
+            let mut request_id = None;
+            let decoded = msg.is_text()
+                .then(|| decode_json_frame(msg.as_text().unwrap_or_default(), WireProtocol::Envelope, &mut request_id));
+            let document_id = match &decoded {
+                Some(Ok(envelope)) => envelope.document_id.clone(),
+                _ => None,
+            };
+
+            // Control frames don't count against the rate limits, and messages
+            // for a document count against its room's too
+            let verdict = if msg.is_text() || msg.is_binary() {
+                state.ws_manager.check_rate(&mut limiter, document_id.as_deref())
+            } else {
+                Verdict::Allow
+            };
+            if verdict == Verdict::Disconnect {
+                warn!("Disconnecting multiplexed connection of user {} for going over its rate limit", user_id);
+                let frames = error_close_frames(WireProtocol::Envelope, &AppError::RateLimitExceeded, axum_tws::CloseCode::POLICY_VIOLATION);
+                for frame in frames {
+                    let _ = outgoing.send(frame).await;
+                }
+                break;
+            }
+
+            let result = match decoded {
+                Some(Ok(_)) if verdict == Verdict::Throttle => Err(AppError::RateLimitExceeded),
+                Some(Ok(envelope)) => handle_multiplexed_message(&client, &mut subscriptions, envelope, request_id.as_deref()).await,
+                Some(Err(e)) => Err(e),
+                None if msg.is_binary() => {
+                    Err(AppError::ValidationError("The multiplexed endpoint only takes JSON envelopes".to_string()))
+                }
+                // Control frames are answered by the WebSocket layer itself
+                None => Ok(()),
+            };
+
+            let reply = match result {
+                Ok(()) if request_id.is_some() => Some(WebSocketMessage::Ack),
+                Ok(()) => None,
+                Err(e) => {
+                    warn!("Failed to handle multiplexed WebSocket message: {}", e);
+                    Some(WebSocketMessage::Error { message: e.public_message() })
+                }
+            };
+            if let Some(frame) = reply.and_then(|reply| reply.to_envelope_frame(request_id.as_deref(), document_id.as_deref())) {
+                let _ = outgoing.send(frame).await;
+            }
+        }
+    };
+
+    // Wait for either side to complete
+    let received_all = tokio::select! {
+        _ = receive => true,
+        _ = (&mut send_task) => false,
+    };
+
+    // Leave every room still subscribed to
+    for (document_id, subscription) in subscriptions {
+        unsubscribe(&state, &document_id, &user_id, subscription).await;
+    }
+
+    // With the forwarders gone, the send task gets its last frames out and stops
+    drop(outgoing);
+    if received_all && tokio::time::timeout(CLOSE_TIMEOUT, &mut send_task).await.is_err() {
+        send_task.abort();
+    }
+    info!("Multiplexed WebSocket connection closed by user {}", user_id);
+}
+
+/// Handle one envelope from a multiplexed client: subscription changes, or a
+/// message for a document it is subscribed to.
+async fn handle_multiplexed_message(
+    client: &MultiplexedClient<'_>,
+    subscriptions: &mut HashMap<String, Subscription>,
+    envelope: Envelope,
+    request_id: Option<&str>,
+) -> Result<(), AppError> {
+    let document_id = envelope.document_id
+        .ok_or_else(|| AppError::ValidationError("Multiplexed messages need a document_id".to_string()))?;
+
+    match envelope.message {
+        WebSocketMessage::Subscribe => {
+            if subscriptions.contains_key(&document_id) {
+                return Ok(());
+            }
+            let connection_id = Uuid::new_v4().to_string();
+            let (ws_manager, user_id) = (&client.state.ws_manager, client.user_id.to_string());
+            let rx = if client.spectator {
+                ws_manager.spectate_document(document_id.clone(), user_id, connection_id.clone()).await
+            } else {
+                ws_manager.join_document(document_id.clone(), user_id, connection_id.clone()).await
+            }?;
+
+            // We are already subscribed, so nothing published after this snapshot is missed
+            let snapshot = match snapshot(client.state, &document_id).await {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    client.state.ws_manager.leave_document(&document_id, client.user_id, &connection_id).await;
+                    return Err(e);
+                }
+            };
+            for frame in snapshot.iter().filter_map(|message| message.to_envelope_frame(None, Some(&document_id))) {
+                let _ = client.outgoing.send(frame).await;
+            }
+
+            let forwarder = spawn_forwarder(
+                client.state.clone(), document_id.clone(), connection_id.clone(), rx, client.outgoing.clone(),
+            );
+            subscriptions.insert(document_id, Subscription { connection_id, forwarder });
+            Ok(())
+        }
+        WebSocketMessage::Unsubscribe => {
+            if let Some(subscription) = subscriptions.remove(&document_id) {
+                unsubscribe(client.state, &document_id, client.user_id, subscription).await;
+            }
+            Ok(())
+        }
+        message => {
+            let subscription = subscriptions.get(&document_id).ok_or_else(|| {
+                AppError::ValidationError(format!("Not subscribed to document {}", document_id))
+            })?;
+            let context = ClientContext {
+                state: client.state,
+                document_id: &document_id,
+                user_id: client.user_id,
+                connection_id: &subscription.connection_id,
+                ip_address: client.ip_address,
+                multiplexed: true,
+                spectator: client.spectator,
+                anonymous: client.anonymous,
+            };
+            handle_json_message(&context, message, WireProtocol::Envelope, request_id, client.outgoing).await
+        }
+    }
+}
+
+async fn unsubscribe(state: &AppState, document_id: &str, user_id: &str, subscription: Subscription) {
+    subscription.forwarder.abort();
+    state.ws_manager.leave_document(document_id, user_id, &subscription.connection_id).await;
+}
+
+/// Forward a room's messages to the socket, tagged with its document, until
+/// the subscription ends.
+fn spawn_forwarder(
+    state: AppState,
+    document_id: String,
+    connection_id: String,
+    mut rx: broadcast::Receiver<RoomMessage>,
+    outgoing: mpsc::Sender<axum_tws::Message>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let messages = match rx.recv().await {
+                Ok(msg) if msg.origin.as_deref() == Some(connection_id.as_str()) => continue,
+                Ok(msg) => vec![msg.message],
+                // Same as on a single-document socket: replace what was dropped with the full state
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Subscription {} lagged {} messages behind, resyncing", connection_id, skipped);
+                    match snapshot(&state, &document_id).await {
+                        Ok(snapshot) => snapshot,
+                        Err(e) => vec![WebSocketMessage::Error { message: e.public_message() }],
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            for frame in messages.iter().filter_map(|message| message.to_envelope_frame(None, Some(&document_id))) {
+                if outgoing.send(frame).await.is_err() {
+                    return;
+                }
+            }
+        }
+    })
+}
+
+/// What a subscriber needs to start from: the current `DocumentState`, and
+/// the room's awareness states if anyone has any.
+async fn snapshot(state: &AppState, document_id: &str) -> Result<Vec<WebSocketMessage>, AppError> {
+    let document = state.database.get_document_crdt_state(document_id).await?;
+    let mut messages = vec![WebSocketMessage::DocumentState { state: document }];
+    let states = state.ws_manager.awareness_states(document_id).await;
+    if !states.is_empty() {
+        messages.push(WebSocketMessage::Awareness { states });
+    }
+    Ok(messages)
+}
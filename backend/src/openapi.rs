@@ -22,7 +22,11 @@ use crate::models::*;
             AuthResponse,
             UpdateUserRoleRequest,
             crate::crdt::DocumentState,
-            crate::crdt::DocumentUpdate
+            crate::crdt::DocumentUpdate,
+            crate::crdt::DiffRequest,
+            crate::crdt::DocumentDiff,
+            crate::crdt::SyncRequest,
+            crate::crdt::UndoResult
         )
     ),
     tags(
@@ -0,0 +1,120 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    response::Json,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use tracing::info;
+use uuid::Uuid;
+use yrs::updates::decoder::Decode;
+use yrs::Update;
+
+use crate::{
+    app::AppState,
+    crdt::{DiffRequest, DocumentDiff, DocumentUpdate, SyncRequest},
+    error::{AppError, AppResult},
+    websocket::{authenticate_connection, spectator_edit, ConnectionIdentity, RoomGuard, RoomMessage, WebSocketMessage},
+};
+
+/// Sync a document over plain request/response, for clients that can't keep
+/// a connection open. The client's updates are applied first; the request is
+/// then held until the document has anything the client doesn't, or the
+/// timeout passes, and answered with a `DocumentDiff` of it (empty if none).
+pub async fn document_sync_handler(
+    Path(document_id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<SyncRequest>,
+) -> AppResult<Json<DocumentDiff>> {
+    info!("Long-poll sync requested for document: {}", document_id);
+
+    let ConnectionIdentity { user_id, spectator, anonymous } = authenticate_connection(&state, &params, &headers, None)?;
+    let max_timeout = state.ws_manager.config().poll_timeout();
+    let timeout = request.timeout_secs.map_or(max_timeout, |secs| Duration::from_secs(secs).min(max_timeout));
+    let deadline = Instant::now() + timeout;
+
+    // Check every update before applying any, so a bad one doesn't leave the others half applied
+    let updates = request.updates.into_iter()
+        .filter(|update| update.as_slice() != Update::EMPTY_V1)
+        .map(|update| match Update::decode_v1(&update) {
+            Ok(_) => Ok(update),
+            Err(e) => Err(AppError::ValidationError(format!("Invalid update: {}", e))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if spectator && !updates.is_empty() {
+        return Err(spectator_edit());
+    }
+    let requested = DiffRequest { state_vector: request.state_vector, since_version: request.since_version };
+
+    // Watch the room before looking at the document, so a change made in between still wakes us
+    let connection_id = Uuid::new_v4().to_string();
+    let rx = state.ws_manager.watch_document(&document_id, &user_id, &connection_id, spectator).await?;
+    let mut watch = RoomWatch { rx, guard: RoomGuard::watching(state.clone(), document_id.clone(), connection_id) };
+
+    // A client that is up to date is still up to date after its own updates,
+    // as long as nobody else's got in between
+    let before = state.database.get_document_crdt_delta(&document_id, &requested).await?;
+    let mut caught_up_to = (before.update == Update::EMPTY_V1).then_some(before.version);
+    for update in updates {
+        let update = DocumentUpdate {
+            content: String::new(),
+            user_id: user_id.clone(),
+            timestamp: chrono::Utc::now().timestamp(),
+            update,
+            version: None,
+            logged_as: None,
+        };
+        // An anonymous ID is new on every request, so nothing could undo it
+        let applied = state.database.apply_crdt_update(&document_id, &update, !anonymous).await?;
+        caught_up_to = caught_up_to.and_then(|version| applied.version.filter(|&applied| applied == version + 1));
+        state.ws_manager.broadcast_update(&document_id, applied, Some(&watch.guard.connection_id)).await;
+    }
+
+    // Otherwise there is something to send already
+    let Some(caught_up_to) = caught_up_to else {
+        return Ok(Json(state.database.get_document_crdt_delta(&document_id, &requested).await?));
+    };
+    let since = DiffRequest { state_vector: Vec::new(), since_version: Some(caught_up_to) };
+    loop {
+        let diff = state.database.get_document_crdt_delta(&document_id, &since).await?;
+        if diff.update != Update::EMPTY_V1 || !watch.changed_before(deadline).await {
+            return Ok(Json(diff));
+        }
+    }
+}
+
+/// A long-poll request's view of its document room.
+struct RoomWatch {
+    rx: broadcast::Receiver<RoomMessage>,
+    /// Stops watching once the request is answered or the client has gone away
+    guard: RoomGuard,
+}
+
+impl RoomWatch {
+    /// Wait for somebody else to change the document. Returns `false` if the
+    /// deadline passes or the server starts shutting down first.
+    async fn changed_before(&mut self, deadline: Instant) -> bool {
+        loop {
+            let received = tokio::select! {
+                received = self.rx.recv() => received,
+                _ = tokio::time::sleep_until(deadline) => return false,
+                _ = self.guard.state.ws_manager.shutdown_signal() => return false,
+            };
+            match received {
+                Ok(msg) if msg.origin.as_deref() == Some(self.guard.connection_id.as_str()) => continue,
+                Ok(msg) => {
+                    if matches!(msg.message, WebSocketMessage::DocumentUpdated { .. } | WebSocketMessage::DocumentState { .. }) {
+                        return true;
+                    }
+                }
+                // Whatever was dropped may have been a change
+                Err(broadcast::error::RecvError::Lagged(_)) => return true,
+                Err(broadcast::error::RecvError::Closed) => return false,
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::time::Instant;
+
+/// A token bucket: holds up to `burst` tokens, refilled at `per_sec` a second.
+#[derive(Debug)]
+pub struct TokenBucket {
+    per_sec: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket. A rate of 0 lets everything through.
+    pub fn new(per_sec: u32, burst: u32) -> Self {
+        Self {
+            per_sec: per_sec as f64,
+            burst: burst.max(1) as f64,
+            tokens: burst.max(1) as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Take a token if there is one.
+    pub fn try_take(&mut self) -> bool {
+        if self.per_sec == 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.burst);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// What to do with a message a client sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Refuse it with `AppError::RateLimitExceeded`
+    Throttle,
+    /// The client keeps going over its limits; close the connection
+    Disconnect,
+}
+
+/// Rate limit of one connection's inbound messages.
+///
+/// Messages over the connection's own limit count against it, and every
+/// message let through pays one of them back, so a client that bursts now
+/// and then is fine, while one that keeps flooding is disconnected once
+/// `max_throttled` are outstanding.
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    bucket: TokenBucket,
+    throttled: u32,
+    max_throttled: u32,
+}
+
+impl ConnectionLimiter {
+    pub fn new(per_sec: u32, burst: u32, max_throttled: u32) -> Self {
+        Self {
+            bucket: TokenBucket::new(per_sec, burst),
+            throttled: 0,
+            max_throttled: max_throttled.max(1),
+        }
+    }
+
+    /// Judge the next message, given whether its room still has room for it.
+    pub fn check(&mut self, room_allows: impl FnOnce() -> bool) -> Verdict {
+        if !self.bucket.try_take() {
+            self.throttled += 1;
+            return if self.throttled >= self.max_throttled { Verdict::Disconnect } else { Verdict::Throttle };
+        }
+        // A busy room isn't this connection's fault, so that doesn't count against it
+        if !room_allows() {
+            return Verdict::Throttle;
+        }
+        self.throttled = self.throttled.saturating_sub(1);
+        Verdict::Allow
+    }
+}
@@ -0,0 +1,247 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgListener, PgPool};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::crdt::LogPosition;
+use crate::websocket::{RoomMessage, WebSocketMessage};
+
+/// Postgres channel room messages are relayed on.
+pub const ROOM_CHANNEL: &str = "room_messages";
+
+/// NOTIFY payloads must stay under 8000 bytes; larger messages are stored
+/// in `relayed_messages` and only their id is sent.
+const MAX_NOTIFY_PAYLOAD: usize = 7900;
+
+/// How long stored messages are kept for listeners to fetch.
+const RELAYED_MESSAGE_RETENTION: Duration = Duration::from_secs(60);
+
+/// Messages waiting to be relayed before new ones are dropped.
+const OUTGOING_CAPACITY: usize = 1024;
+
+/// A room message that was published on another instance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayedMessage {
+    pub document_id: String,
+    pub message: RoomMessage,
+    /// Where the update a `DocumentUpdated` carries was logged, if it was,
+    /// so receivers can tell whether they missed any before it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logged_as: Option<LogPosition>,
+}
+
+/// What other instances relay to this one.
+#[derive(Debug, Clone)]
+pub enum RelayEvent {
+    Message(RelayedMessage),
+    /// Messages were lost on the way, e.g. while the listening connection was
+    /// down, so documents should catch up with the update log
+    Missed,
+}
+
+/// Carries room messages between the instances serving the same documents,
+/// so that users connected to different replicas see each other.
+pub trait RoomRelay: Send + Sync + std::fmt::Debug {
+    /// Hand a message published to a local room over to the other instances.
+    fn publish(&self, document_id: &str, message: &RoomMessage);
+
+    /// Messages published on other instances, or `None` if there are none
+    /// to expect.
+    fn subscribe(&self) -> Option<broadcast::Receiver<RelayEvent>>;
+}
+
+/// Single-node mode: rooms only exist in this process, so there is nothing to relay.
+#[derive(Debug, Default)]
+pub struct LocalRelay;
+
+impl RoomRelay for LocalRelay {
+    fn publish(&self, _document_id: &str, _message: &RoomMessage) {}
+
+    fn subscribe(&self) -> Option<broadcast::Receiver<RelayEvent>> {
+        None
+    }
+}
+
+/// What goes over `ROOM_CHANNEL`: a message, where to fetch one that was
+/// too large, or word that some were lost.
+#[derive(Debug, Serialize, Deserialize)]
+enum Notification {
+    Message { instance: Uuid, relayed: RelayedMessage },
+    Stored { instance: Uuid, id: i64 },
+    Dropped { instance: Uuid },
+}
+
+/// Relays room messages through Postgres `LISTEN/NOTIFY`.
+///
+/// Messages are published in order by a background task, and another one
+/// listens on a dedicated connection, skipping what this instance sent itself.
+/// Messages that are lost, because the publishing queue was full or the
+/// listening connection was down, are reported as `RelayEvent::Missed`.
+#[derive(Debug)]
+pub struct PostgresRelay {
+    outgoing: mpsc::Sender<RelayedMessage>,
+    incoming: broadcast::Sender<RelayEvent>,
+    /// Set when a message couldn't be queued, until the others are told
+    dropped: Arc<AtomicBool>,
+}
+
+impl PostgresRelay {
+    pub fn new(pool: PgPool, capacity: usize) -> Self {
+        let instance = Uuid::new_v4();
+        let (outgoing, queue) = mpsc::channel(OUTGOING_CAPACITY);
+        let (incoming, _) = broadcast::channel(capacity);
+        let dropped = Arc::new(AtomicBool::new(false));
+        tokio::spawn(publish_loop(pool.clone(), instance, queue, dropped.clone()));
+        tokio::spawn(listen_loop(pool, instance, incoming.downgrade()));
+        Self { outgoing, incoming, dropped }
+    }
+}
+
+impl RoomRelay for PostgresRelay {
+    fn publish(&self, document_id: &str, message: &RoomMessage) {
+        let logged_as = match &message.message {
+            WebSocketMessage::DocumentUpdated { update } => update.logged_as,
+            _ => None,
+        };
+        let relayed = RelayedMessage { document_id: document_id.to_string(), message: message.clone(), logged_as };
+        if self.outgoing.try_send(relayed).is_err() {
+            warn!("Relay queue is full, dropping a message for document {}", document_id);
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn subscribe(&self) -> Option<broadcast::Receiver<RelayEvent>> {
+        Some(self.incoming.subscribe())
+    }
+}
+
+async fn publish_loop(pool: PgPool, instance: Uuid, mut queue: mpsc::Receiver<RelayedMessage>, dropped: Arc<AtomicBool>) {
+    let mut last_cleanup = Instant::now();
+    while let Some(relayed) = queue.recv().await {
+        if let Err(e) = publish(&pool, instance, relayed).await {
+            warn!("Failed to relay room message: {}", e);
+        }
+        if dropped.swap(false, Ordering::SeqCst) {
+            let payload = serde_json::to_string(&Notification::Dropped { instance }).unwrap_or_default();
+            if let Err(e) = sqlx::query!("SELECT pg_notify($1, $2)", ROOM_CHANNEL, payload).execute(&pool).await {
+                warn!("Failed to report dropped room messages: {}", e);
+                dropped.store(true, Ordering::SeqCst);
+            }
+        }
+
+        if last_cleanup.elapsed() >= RELAYED_MESSAGE_RETENTION {
+            last_cleanup = Instant::now();
+            let cutoff = chrono::Utc::now() - RELAYED_MESSAGE_RETENTION;
+            if let Err(e) = sqlx::query!("DELETE FROM relayed_messages WHERE created_at < $1", cutoff)
+                .execute(&pool)
+                .await
+            {
+                warn!("Failed to clean up relayed messages: {}", e);
+            }
+        }
+    }
+}
+
+async fn publish(pool: &PgPool, instance: Uuid, relayed: RelayedMessage) -> Result<(), sqlx::Error> {
+    let mut payload = serde_json::to_string(&Notification::Message { instance, relayed }).unwrap_or_default();
+    if payload.len() > MAX_NOTIFY_PAYLOAD {
+        let row = sqlx::query!(
+            "INSERT INTO relayed_messages (payload) VALUES ($1) RETURNING id",
+            payload
+        )
+        .fetch_one(pool)
+        .await?;
+        payload = serde_json::to_string(&Notification::Stored { instance, id: row.id }).unwrap_or_default();
+    }
+
+    sqlx::query!("SELECT pg_notify($1, $2)", ROOM_CHANNEL, payload)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Listen on `ROOM_CHANNEL` until the relay is dropped, reconnecting as needed.
+async fn listen_loop(pool: PgPool, instance: Uuid, incoming: broadcast::WeakSender<RelayEvent>) {
+    let mut reconnecting = false;
+    loop {
+        let mut listener = match PgListener::connect_with(&pool).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Cannot connect the room relay listener: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        if let Err(e) = listener.listen(ROOM_CHANNEL).await {
+            warn!("Cannot listen for relayed room messages: {}", e);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+        info!("Relaying room messages through Postgres channel {}", ROOM_CHANNEL);
+
+        // Whatever was sent while we weren't listening is lost
+        if reconnecting {
+            let Some(incoming) = incoming.upgrade() else { return };
+            let _ = incoming.send(RelayEvent::Missed);
+        }
+        reconnecting = true;
+
+        loop {
+            let notification = match listener.try_recv().await {
+                Ok(Some(notification)) => notification,
+                // Reconnect here rather than let the listener do it on the next
+                // call, so we know when we are listening again
+                Ok(None) => {
+                    warn!("Room relay listener lost its connection, catching up once reconnected");
+                    break;
+                }
+                Err(e) => {
+                    warn!("Room relay listener failed: {}", e);
+                    break;
+                }
+            };
+
+            let Some(incoming) = incoming.upgrade() else { return };
+            match receive(&pool, instance, notification.payload()).await {
+                Ok(Some(event)) => {
+                    let _ = incoming.send(event);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Dropping relayed room message: {}", e);
+                    let _ = incoming.send(RelayEvent::Missed);
+                }
+            }
+        }
+    }
+}
+
+/// Decode a notification, or `None` if this instance sent it.
+async fn receive(pool: &PgPool, instance: Uuid, payload: &str) -> Result<Option<RelayEvent>, String> {
+    let notification: Notification = serde_json::from_str(payload).map_err(|e| e.to_string())?;
+    match notification {
+        Notification::Message { instance: sender, .. }
+        | Notification::Stored { instance: sender, .. }
+        | Notification::Dropped { instance: sender } if sender == instance => Ok(None),
+        Notification::Message { relayed, .. } => Ok(Some(RelayEvent::Message(relayed))),
+        Notification::Dropped { .. } => Ok(Some(RelayEvent::Missed)),
+        Notification::Stored { id, .. } => {
+            let row = sqlx::query!("SELECT payload FROM relayed_messages WHERE id = $1", id)
+                .fetch_optional(pool)
+                .await
+                .map_err(|e| e.to_string())?
+                .ok_or_else(|| format!("relayed message {} is gone", id))?;
+            match serde_json::from_str(&row.payload).map_err(|e| e.to_string())? {
+                Notification::Message { relayed, .. } => Ok(Some(RelayEvent::Message(relayed))),
+                Notification::Stored { .. } | Notification::Dropped { .. } => {
+                    Err(format!("relayed message {} points elsewhere", id))
+                }
+            }
+        }
+    }
+}
@@ -2,6 +2,18 @@ use axum::http::HeaderMap;
 use axum::extract::Request;
 use tracing::{debug, info, warn};
 
+/// Value of the cookie named `name`, if the request carries one.
+pub fn extract_cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(axum::http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
 /// Extract client IP address from headers only (without ConnectInfo)
 pub fn extract_client_ip_from_headers(headers: &HeaderMap) -> String {
     debug!("Starting IP extraction from headers");
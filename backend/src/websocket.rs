@@ -1,33 +1,312 @@
 use axum::{
-    extract::{Path, State},
-    response::IntoResponse,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
 };
 use axum_tws::{WebSocket, WebSocketUpgrade};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::{broadcast, RwLock};
-use tracing::{info, error};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, watch, RwLock};
+use tracing::{debug, info, error, warn};
 use uuid::Uuid;
+use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt};
+use yrs::encoding::read::Cursor;
+use yrs::sync::awareness::{AwarenessUpdate, AwarenessUpdateEntry};
+use yrs::sync::{Message as SyncProtocolMessage, MessageReader, SyncMessage};
+use yrs::updates::decoder::DecoderV1;
+use yrs::updates::encoder::Encode;
+use yrs::{StateVector, Update};
 
 use crate::{
+    actor::CaughtUp,
     app::AppState,
-    crdt::{DocumentUpdate, DocumentState},
+    auth::authenticate_token,
+    config::WebSocketConfig,
+    crdt::{DiffRequest, DocumentDiff, DocumentUpdate, DocumentState},
+    error::{AppError, AppResult},
+    models::UpdateDocumentRequest,
+    rate_limit::{ConnectionLimiter, TokenBucket, Verdict},
+    relay::{LocalRelay, RelayEvent, RoomRelay},
+    utils::{extract_client_ip_from_headers, extract_cookie},
 };
+use validator::Validate;
+
+/// Subprotocol a client can request to speak the y-protocols binary framing
+/// (as used by `y-websocket`) from the start of the connection.
+pub const YJS_SUBPROTOCOL: &str = "y-protocols";
+
+/// Subprotocol a JSON client requests to exchange versioned `Envelope`s,
+/// with request IDs and acknowledgements, instead of bare messages.
+pub const ENVELOPE_SUBPROTOCOL: &str = "collab.v2";
+
+/// Subprotocol a JSON client requests to exchange bare messages, so the
+/// server doesn't have to wait for its first frame to tell what it speaks.
+pub const JSON_SUBPROTOCOL: &str = "collab.v1";
+
+/// Query parameter (`?protocol=json`, `yjs` or `envelope`) naming the
+/// protocol, for clients that can't choose their subprotocols.
+pub const PROTOCOL_PARAM: &str = "protocol";
+
+/// Version of the `Envelope` protocol this server speaks. Bare messages,
+/// as sent by clients that don't negotiate `ENVELOPE_SUBPROTOCOL`, are version 1.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Browsers can't set an `Authorization` header on a WebSocket, so a JWT can
+/// also be offered as a `Sec-WebSocket-Protocol` entry with this prefix...
+pub const TOKEN_SUBPROTOCOL_PREFIX: &str = "bearer.";
+
+/// ...or in this cookie (named like the frontend's storage key), or in a `token` query parameter.
+pub const TOKEN_COOKIE: &str = "auth_token";
+
+/// Query parameter (`?spectator=true`) that makes a connection a spectator,
+/// as a token with `READ_ONLY_SCOPE` always does.
+pub const SPECTATOR_PARAM: &str = "spectator";
+
+/// Without a negotiated subprotocol or `PROTOCOL_PARAM` we wait this long for the client's first
+/// frame to tell whether it speaks JSON or y-protocols before assuming JSON.
+const PROTOCOL_DETECTION_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Awareness entries that aren't renewed within this long are dropped. Same as
+/// the Yjs awareness `outdatedTimeout`; Yjs clients renew theirs every 15s.
+pub const AWARENESS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often rooms are swept for awareness entries past `AWARENESS_TIMEOUT`.
+const AWARENESS_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often `WebSocketManager::drained` checks for remaining connections.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long a connection that is done receiving gets to send its last frames,
+/// e.g. to a client that has stopped reading.
+pub(crate) const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Presence a JSON client publishes about itself. Positions are UTF-16
+/// offsets into the document text, like everywhere else in the CRDT layer.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Presence {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selection: Option<Selection>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<PresenceUser>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Selection {
+    pub anchor: u32,
+    pub head: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PresenceUser {
+    pub name: String,
+    pub color: String,
+}
+
+/// One client's entry in a room's awareness, as in the Yjs awareness protocol.
+/// `state` is a JSON client's `Presence` or whatever a Yjs client set, and
+/// `None` once the client has gone away.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AwarenessState {
+    pub client_id: u64,
+    pub clock: u32,
+    pub state: Option<serde_json::Value>,
+}
+
+impl AwarenessState {
+    fn from_update(update: AwarenessUpdate) -> Result<Vec<Self>, AppError> {
+        update.clients.into_iter().map(|(client_id, entry)| {
+            let state = serde_json::from_str(&entry.json)
+                .map_err(|e| AppError::ValidationError(format!("Malformed awareness state: {}", e)))?;
+            Ok(Self {
+                client_id,
+                clock: entry.clock,
+                state: match state {
+                    serde_json::Value::Null => None,
+                    state => Some(state),
+                },
+            })
+        }).collect()
+    }
+
+    fn to_update(states: &[Self]) -> AwarenessUpdate {
+        let clients = states.iter().map(|s| {
+            let json = s.state.as_ref().map_or_else(|| "null".to_string(), |state| state.to_string());
+            (s.client_id, AwarenessUpdateEntry { clock: s.clock, json: json.into() })
+        }).collect();
+        AwarenessUpdate { clients }
+    }
+}
+
+/// Last awareness state a client published and the connection it came over.
+#[derive(Debug)]
+struct AwarenessEntry {
+    connection_id: String,
+    clock: u32,
+    state: serde_json::Value,
+    renewed_at: Instant,
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum WebSocketMessage {
     // Client -> Server
     JoinDocument { document_id: String, user_id: String },
     UpdateDocument { content: String, user_id: String },
+    /// Publish this connection's cursor, selection and user info. Re-send it
+    /// (changed or not) well within `AWARENESS_TIMEOUT` to keep it alive.
+    UpdatePresence { presence: Presence },
+    /// Undo/redo this connection's own latest edit
+    Undo,
+    Redo,
+    /// Ask for only what the client is missing, see `DiffRequest`
+    RequestDiff {
+        #[serde(default)]
+        state_vector: Vec<u8>,
+        #[serde(default)]
+        since_version: Option<u64>,
+    },
     
     // Server -> Client
     DocumentState { state: DocumentState },
+    DocumentDiff { diff: DocumentDiff },
     UserJoined { user_id: String },
     UserLeft { user_id: String },
     DocumentUpdated { update: DocumentUpdate },
     Error { message: String },
+    /// Changed awareness entries of the room; never persisted
+    Awareness { states: Vec<AwarenessState> },
+    /// The server is shutting down and closes the connection right after;
+    /// reconnect in a moment to resume editing
+    ServerRestarting,
+    /// The request with the envelope's ID was handled, after any reply it triggered
+    Ack,
+
+    // Client -> Server, on the multiplexed endpoint only
+    /// Join the room of the envelope's document
+    Subscribe,
+    /// Leave the room of the envelope's document
+    Unsubscribe,
+}
+
+/// A `WebSocketMessage` as exchanged over `ENVELOPE_SUBPROTOCOL`. `id` is
+/// assigned by the client; a request that has one is answered with an `Ack`
+/// or an `Error` carrying the same ID. Room broadcasts have no ID.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Envelope<M = WebSocketMessage> {
+    pub v: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Document the message is about, on the multiplexed endpoint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub document_id: Option<String>,
+    pub message: M,
+}
+
+/// Wire format spoken by a single connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireProtocol {
+    /// Externally tagged `WebSocketMessage` JSON in text frames
+    Json,
+    /// The same messages wrapped in `Envelope`s
+    Envelope,
+    /// y-protocols sync/awareness messages in binary frames
+    Yjs,
+}
+
+impl WireProtocol {
+    /// The `Sec-WebSocket-Protocol` entry that selects this protocol.
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            WireProtocol::Json => JSON_SUBPROTOCOL,
+            WireProtocol::Envelope => ENVELOPE_SUBPROTOCOL,
+            WireProtocol::Yjs => YJS_SUBPROTOCOL,
+        }
+    }
+}
+
+impl WebSocketMessage {
+    /// Encode this message for a connection speaking `protocol`.
+    /// Returns `None` for messages that have no equivalent in that protocol.
+    pub fn to_frame(&self, protocol: WireProtocol) -> Option<axum_tws::Message> {
+        self.to_reply_frame(protocol, None)
+    }
+
+    /// Like `to_frame`, answering the request with ID `request_id` if the
+    /// protocol has request IDs.
+    pub fn to_reply_frame(&self, protocol: WireProtocol, request_id: Option<&str>) -> Option<axum_tws::Message> {
+        match protocol {
+            WireProtocol::Json => serde_json::to_string(self).ok().map(axum_tws::Message::text),
+            WireProtocol::Envelope => self.to_envelope_frame(request_id, None),
+            WireProtocol::Yjs => match self {
+                WebSocketMessage::DocumentUpdated { update } if !update.update.is_empty() => Some(sync_frame(
+                    SyncProtocolMessage::Sync(SyncMessage::Update(update.update.clone())),
+                )),
+                WebSocketMessage::Awareness { states } => Some(sync_frame(
+                    SyncProtocolMessage::Awareness(AwarenessState::to_update(states)),
+                )),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl WebSocketMessage {
+    /// Encode this message in an `Envelope`, tagged with `document_id` if given.
+    pub fn to_envelope_frame(&self, request_id: Option<&str>, document_id: Option<&str>) -> Option<axum_tws::Message> {
+        let envelope = Envelope {
+            v: PROTOCOL_VERSION,
+            id: request_id.map(str::to_string),
+            document_id: document_id.map(str::to_string),
+            message: self,
+        };
+        serde_json::to_string(&envelope).ok().map(axum_tws::Message::text)
+    }
+}
+
+fn sync_frame(message: SyncProtocolMessage) -> axum_tws::Message {
+    axum_tws::Message::binary(message.encode_v1())
+}
+
+/// A message published to a document room, tagged with the connection it
+/// came from so it isn't echoed back to its sender.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomMessage {
+    pub origin: Option<String>,
+    pub message: WebSocketMessage,
+}
+
+impl From<WebSocketMessage> for RoomMessage {
+    fn from(message: WebSocketMessage) -> Self {
+        Self { origin: None, message }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct WebSocketMetrics {
+    /// Documents with a broadcast channel
+    pub rooms: usize,
+    pub connections: usize,
+    /// Documents with live awareness entries
+    pub awareness_rooms: usize,
+}
+
+/// Distinct users connected to a document room, by what they may do there.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ActiveUsers {
+    pub editors: usize,
+    pub spectators: usize,
+}
+
+/// Connections on this instance, against the caps in `[websocket]`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionCounts {
+    /// Connections to the document asked about
+    pub document: usize,
+    pub total: usize,
 }
 
 #[derive(Debug)]
@@ -35,115 +314,438 @@ pub struct WebSocketConnection {
     pub id: String,
     pub user_id: String,
     pub document_id: String,
+    /// Awareness client ID the presence of a JSON client is published under
+    pub awareness_client_id: u64,
+    /// Receives the room's messages, but may not edit
+    pub spectator: bool,
 }
 
 #[derive(Debug)]
 pub struct WebSocketManager {
     connections: Arc<RwLock<HashMap<String, WebSocketConnection>>>,
-    document_rooms: Arc<RwLock<HashMap<String, broadcast::Sender<WebSocketMessage>>>>,
+    document_rooms: Arc<RwLock<HashMap<String, broadcast::Sender<RoomMessage>>>>,
+    /// Awareness entries of each document room, by client ID
+    awareness: Arc<RwLock<HashMap<String, HashMap<u64, AwarenessEntry>>>>,
+    /// How many more inbound messages each document room takes right now
+    room_limits: Mutex<HashMap<String, TokenBucket>>,
+    config: WebSocketConfig,
+    /// Carries room messages to and from other instances
+    relay: Arc<dyn RoomRelay>,
+    /// Set once the server starts shutting down
+    shutdown: watch::Sender<bool>,
 }
 
 impl WebSocketManager {
     pub fn new() -> Self {
+        Self::with_config(WebSocketConfig::default())
+    }
+
+    pub fn with_config(config: WebSocketConfig) -> Self {
+        Self::with_relay(config, Arc::new(LocalRelay))
+    }
+
+    pub fn with_relay(config: WebSocketConfig, relay: Arc<dyn RoomRelay>) -> Self {
         Self {
             connections: Arc::new(RwLock::new(HashMap::new())),
             document_rooms: Arc::new(RwLock::new(HashMap::new())),
+            awareness: Arc::new(RwLock::new(HashMap::new())),
+            room_limits: Mutex::new(HashMap::new()),
+            config,
+            relay,
+            shutdown: watch::Sender::new(false),
+        }
+    }
+
+    pub fn relay(&self) -> &dyn RoomRelay {
+        self.relay.as_ref()
+    }
+
+    pub fn config(&self) -> &WebSocketConfig {
+        &self.config
+    }
+
+    /// Refuse new connections, and tell every open one the server is
+    /// restarting before closing it.
+    pub fn shut_down(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Resolves once `shut_down` is called, for connections to `select!` on.
+    pub fn shutdown_signal(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut shutdown = self.shutdown.subscribe();
+        // The borrow `wait_for` returns isn't `Send`, so it is dropped right away
+        async move { drop(shutdown.wait_for(|&shutting_down| shutting_down).await) }
+    }
+
+    /// Rate limit of a new connection's inbound messages.
+    pub fn connection_limiter(&self) -> ConnectionLimiter {
+        ConnectionLimiter::new(self.config.messages_per_sec, self.config.message_burst, self.config.max_throttled_messages)
+    }
+
+    /// Judge an inbound message against its connection's limit and, if it
+    /// is for a document, that document room's.
+    pub fn check_rate(&self, limiter: &mut ConnectionLimiter, document_id: Option<&str>) -> Verdict {
+        limiter.check(|| {
+            let Some(document_id) = document_id else { return true };
+            let mut room_limits = self.room_limits.lock().unwrap();
+            room_limits.entry(document_id.to_string())
+                .or_insert_with(|| TokenBucket::new(self.config.room_messages_per_sec, self.config.room_message_burst))
+                .try_take()
+        })
+    }
+
+    /// Wait until every connection has left its room.
+    pub async fn drained(&self) {
+        while !self.connections.read().await.is_empty() {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
         }
     }
 
+    /// Periodically drop awareness entries that haven't been renewed in time,
+    /// until the manager itself is dropped.
+    pub fn spawn_awareness_reaper(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let manager = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(AWARENESS_SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(manager) = Weak::upgrade(&manager) else { break };
+                manager.expire_awareness(AWARENESS_TIMEOUT).await;
+            }
+        })
+    }
+
     /*
     * This function is used to join a document room.
     * It creates a new broadcast channel if the document room does not exist.
     * It also sends a join message to all other users in the document room.
     * It returns a receiver that can be used to receive messages from the document room.
     * 
-    * Note that it used to have a deadlock issue, but it was fixed by using a write lock on the rooms HashMap.
-    * This is because tx.send() is a blocking operation, and it acquires a lock on the broadcast channel.
-    * The write lock is released after the join message is sent, which prevents the deadlock.
+    * Note that it used to have a deadlock issue: the join message was sent while
+    * still holding the write lock on the rooms HashMap.
     * 
-    * Why This Fixes the Deadlock:
+    * Why sending it here fixes the deadlock:
     *   Before (Deadlock):
     *    1. Thread A acquires write lock
     *    2. Thread A calls tx.send() (blocks if channel full)
     *    3. Thread B tries to acquire write lock (waits for Thread A)
     *    4. DEADLOCK! Thread A waiting for send, Thread B waiting for lock
     *   After (No Deadlock):
-    *    1. Thread A acquires write lock
-    *    2. Thread A gets tx and releases lock immediately
-    *    3. Thread A calls tx.send() (no lock held)
-    *    4. Thread B can acquire lock normally
-    *    5. No deadlock!
+    *    1. enter_room acquires the write lock, gets tx and releases the lock
+    *    2. join_document calls tx.send() with no lock held
+    *    3. Thread B can acquire lock normally
     *    The key insight is that tx.send() can block, so we must release the lock before calling it.
     */
-    pub async fn join_document(&self, document_id: String, user_id: String, connection_id: String) -> broadcast::Receiver<WebSocketMessage> {
-        // Store the connection
+    pub async fn join_document(&self, document_id: String, user_id: String, connection_id: String) -> AppResult<broadcast::Receiver<RoomMessage>> {
+        // Takes and releases the connections and rooms locks
+        let (tx, rx) = self.enter_room(&document_id, &user_id, &connection_id, false).await?;
+
+        // No lock is held here, so sending can't deadlock with another join
+        let joined: RoomMessage = WebSocketMessage::UserJoined { user_id }.into();
+        self.relay.publish(&document_id, &joined);
+        let _ = tx.send(joined);
+
+        Ok(rx)
+    }
+
+    /// Join a room as a spectator. Spectators come and go unannounced, so a
+    /// large audience doesn't flood the room with `UserJoined`/`UserLeft`.
+    pub async fn spectate_document(&self, document_id: String, user_id: String, connection_id: String) -> AppResult<broadcast::Receiver<RoomMessage>> {
+        Ok(self.enter_room(&document_id, &user_id, &connection_id, true).await?.1)
+    }
+
+    /// Join a room without announcing it, for a client that only waits for
+    /// the room's next change instead of taking part in it. It still counts
+    /// as a connection until `unwatch_document`.
+    pub async fn watch_document(&self, document_id: &str, user_id: &str, connection_id: &str, spectator: bool) -> AppResult<broadcast::Receiver<RoomMessage>> {
+        Ok(self.enter_room(document_id, user_id, connection_id, spectator).await?.1)
+    }
+
+    pub async fn unwatch_document(&self, document_id: &str, connection_id: &str) {
+        self.connections.write().await.remove(connection_id);
+        self.remove_room_if_empty(document_id).await;
+    }
+
+    /// Register the connection and subscribe it to the room's channel,
+    /// creating the room if need be. Fails if that would go over one of the
+    /// connection caps.
+    ///
+    /// Each lock is released before the next is taken, and both are released
+    /// before returning, so callers can send to the channel without holding
+    /// either (see `join_document`).
+    async fn enter_room(
+        &self,
+        document_id: &str,
+        user_id: &str,
+        connection_id: &str,
+        spectator: bool,
+    ) -> AppResult<(broadcast::Sender<RoomMessage>, broadcast::Receiver<RoomMessage>)> {
+        // Store the connection, checking the caps under the same lock so that
+        // a burst of joins can't all squeeze in under them
         {
             let mut connections = self.connections.write().await;
-            connections.insert(connection_id.clone(), WebSocketConnection {
-                id: connection_id.clone(),
-                user_id: user_id.clone(),
-                document_id: document_id.clone(),
+            self.check_connection_caps(&connections, document_id, user_id)?;
+            connections.insert(connection_id.to_string(), WebSocketConnection {
+                id: connection_id.to_string(),
+                user_id: user_id.to_string(),
+                document_id: document_id.to_string(),
+                // Yjs client IDs are random 32-bit numbers, so we pick ours the same way
+                awareness_client_id: Uuid::new_v4().as_u128() as u32 as u64,
+                spectator,
             });
         }
 
-        // 1. Create a new scope with curly braces
-        let (tx, rx) = {
-            // 2. Acquire write lock on the rooms HashMap
-            let mut rooms = self.document_rooms.write().await;
-            
-            // 3. Check if this document already has a broadcast channel
-            if let Some(tx) = rooms.get(&document_id) {
-                // 4a. If it exists, clone the sender and create a new receiver
-                (tx.clone(), tx.subscribe())
-            } else {
-                // 4b. If it doesn't exist, create a new broadcast channel
-                let (tx, rx) = broadcast::channel(100);
-                // 5. Store the sender in the HashMap
-                rooms.insert(document_id.clone(), tx.clone());
-                (tx, rx)
-            }
-        }; // 6. Write lock is released here (end of scope)
+        // The rooms lock is only held to find or create the channel; nothing
+        // is sent under it
+        let mut rooms = self.document_rooms.write().await;
+        if let Some(tx) = rooms.get(document_id) {
+            Ok((tx.clone(), tx.subscribe()))
+        } else {
+            let (tx, rx) = broadcast::channel(self.config.channel_capacity);
+            rooms.insert(document_id.to_string(), tx.clone());
+            Ok((tx, rx))
+        }
+    }
 
-        // 7. Send the join message AFTER releasing the lock (prevents deadlock)
-        let _ = tx.send(WebSocketMessage::UserJoined { user_id: user_id.clone() });
-        
-        // 8. Return the receiver
-        rx
+    /// Refuse a connection that would take the server, the document or the
+    /// user past its cap. A cap of 0 means no cap.
+    fn check_connection_caps(
+        &self,
+        connections: &HashMap<String, WebSocketConnection>,
+        document_id: &str,
+        user_id: &str,
+    ) -> AppResult<()> {
+        let reached = |cap: usize, count: usize| cap != 0 && count >= cap;
+        let reason = if reached(self.config.max_total_connections, connections.len()) {
+            "Server has too many connections"
+        } else if reached(
+            self.config.max_connections_per_document,
+            connections.values().filter(|conn| conn.document_id == document_id).count(),
+        ) {
+            "Document has too many connections"
+        } else if reached(
+            self.config.max_connections_per_user,
+            connections.values().filter(|conn| conn.user_id == user_id).count(),
+        ) {
+            "User has too many connections"
+        } else {
+            return Ok(());
+        };
+        Err(AppError::ServiceUnavailable(reason.to_string()))
     }
 
     pub async fn leave_document(&self, document_id: &str, user_id: &str, connection_id: &str) {
         // Remove the connection
-        {
+        let removed = {
             let mut connections = self.connections.write().await;
-            connections.remove(connection_id);
+            connections.remove(connection_id)
+        };
+
+        self.remove_awareness(document_id, |entry| entry.connection_id == connection_id).await;
+
+        if !removed.is_some_and(|conn| conn.spectator) {
+            self.publish(document_id, WebSocketMessage::UserLeft { user_id: user_id.to_string() }.into()).await;
         }
 
-        let rooms = self.document_rooms.read().await;
-        if let Some(tx) = rooms.get(document_id) {
-            let _ = tx.send(WebSocketMessage::UserLeft { user_id: user_id.to_string() });
+        self.remove_room_if_empty(document_id).await;
+    }
+
+    /// Drop a room's channel once no connection is in it any more.
+    ///
+    /// Connections are counted rather than `receiver_count()`, since the leaving
+    /// connection's receiver may not have been dropped yet. This holds the rooms
+    /// lock while reading `connections`, which `join_document` never does the
+    /// other way around (it registers the connection and releases that lock
+    /// first), so the two can't deadlock. A join that registers after our check
+    /// simply creates a fresh room once we release the lock, and one that
+    /// registered before keeps this room alive.
+    async fn remove_room_if_empty(&self, document_id: &str) {
+        let mut rooms = self.document_rooms.write().await;
+        let occupied = self.connections.read().await
+            .values()
+            .any(|conn| conn.document_id == document_id);
+        if !occupied {
+            rooms.remove(document_id);
+            self.room_limits.lock().unwrap().remove(document_id);
         }
     }
 
-    pub async fn get_active_users_count(&self, document_id: &str) -> usize {
+    /// Live rooms and connections, for monitoring.
+    pub async fn metrics(&self) -> WebSocketMetrics {
+        WebSocketMetrics {
+            rooms: self.document_rooms.read().await.len(),
+            connections: self.connections.read().await.len(),
+            awareness_rooms: self.awareness.read().await.len(),
+        }
+    }
+
+    pub async fn connection_counts(&self, document_id: &str) -> ConnectionCounts {
+        let connections = self.connections.read().await;
+        ConnectionCounts {
+            document: connections.values().filter(|conn| conn.document_id == document_id).count(),
+            total: connections.len(),
+        }
+    }
+
+    pub async fn get_active_users_count(&self, document_id: &str) -> ActiveUsers {
         let connections = self.connections.read().await;
-        connections.values()
-            .filter(|conn| conn.document_id == document_id)
+        let count = |spectator: bool| connections.values()
+            .filter(|conn| conn.document_id == document_id && conn.spectator == spectator)
             .map(|conn| &conn.user_id)
             .collect::<std::collections::HashSet<_>>()
-            .len()
+            .len();
+        ActiveUsers { editors: count(false), spectators: count(true) }
     }
 
-    pub async fn broadcast_update(&self, document_id: &str, update: DocumentUpdate) {
-        let rooms = self.document_rooms.read().await;
-        if let Some(tx) = rooms.get(document_id) {
-            let _ = tx.send(WebSocketMessage::DocumentUpdated { update });
-        }
+    /// Publish an update to the room, skipping the `origin` connection if given.
+    pub async fn broadcast_update(&self, document_id: &str, update: DocumentUpdate, origin: Option<&str>) {
+        self.publish(document_id, RoomMessage {
+            origin: origin.map(str::to_string),
+            message: WebSocketMessage::DocumentUpdated { update },
+        }).await;
     }
 
     pub async fn broadcast_state(&self, document_id: &str, state: DocumentState) {
+        self.publish(document_id, WebSocketMessage::DocumentState { state }.into()).await;
+    }
+
+    pub async fn broadcast_awareness(&self, document_id: &str, states: Vec<AwarenessState>, origin: Option<&str>) {
+        self.publish(document_id, RoomMessage {
+            origin: origin.map(str::to_string),
+            message: WebSocketMessage::Awareness { states },
+        }).await;
+    }
+
+    /// Send a message to the room here and on every other instance.
+    async fn publish(&self, document_id: &str, message: RoomMessage) {
+        self.relay.publish(document_id, &message);
+        self.deliver(document_id, message).await;
+    }
+
+    /// Send a message to the room's connections on this instance only, e.g.
+    /// one another instance relayed to us.
+    pub async fn deliver(&self, document_id: &str, message: RoomMessage) {
         let rooms = self.document_rooms.read().await;
         if let Some(tx) = rooms.get(document_id) {
-            let _ = tx.send(WebSocketMessage::DocumentState { state });
+            let _ = tx.send(message);
+        }
+    }
+
+    /// Current awareness entries of a room, for clients that just joined.
+    pub async fn awareness_states(&self, document_id: &str) -> Vec<AwarenessState> {
+        let awareness = self.awareness.read().await;
+        awareness.get(document_id).map_or_else(Vec::new, |room| {
+            room.iter().map(|(&client_id, entry)| AwarenessState {
+                client_id,
+                clock: entry.clock,
+                state: Some(entry.state.clone()),
+            }).collect()
+        })
+    }
+
+    /// Record awareness states published over `connection_id` and relay the
+    /// ones that are newer than what we have to the rest of the room.
+    pub async fn apply_awareness(&self, document_id: &str, connection_id: &str, states: Vec<AwarenessState>) {
+        let accepted: Vec<AwarenessState> = {
+            let mut awareness = self.awareness.write().await;
+            let room = awareness.entry(document_id.to_string()).or_default();
+            let now = Instant::now();
+            states.into_iter().filter(|update| {
+                let current = room.get(&update.client_id);
+                // Same rule as Yjs: newer clocks win, and a removal wins a tie
+                let newer = current.is_none_or(|entry| {
+                    update.clock > entry.clock || (update.clock == entry.clock && update.state.is_none())
+                });
+                if !newer {
+                    // Re-sending the current state still renews it
+                    if let Some(entry) = room.get_mut(&update.client_id)
+                        && update.clock == entry.clock
+                    {
+                        entry.renewed_at = now;
+                    }
+                    return false;
+                }
+                match &update.state {
+                    Some(state) => {
+                        room.insert(update.client_id, AwarenessEntry {
+                            connection_id: connection_id.to_string(),
+                            clock: update.clock,
+                            state: state.clone(),
+                            renewed_at: now,
+                        });
+                    }
+                    None => {
+                        room.remove(&update.client_id);
+                    }
+                }
+                true
+            }).collect()
+        };
+
+        if !accepted.is_empty() {
+            self.broadcast_awareness(document_id, accepted, Some(connection_id)).await;
+        }
+    }
+
+    /// Publish a JSON client's presence under its connection's awareness client ID.
+    pub async fn update_presence(&self, document_id: &str, connection_id: &str, presence: &Presence) {
+        let Some(client_id) = self.connections.read().await
+            .get(connection_id)
+            .map(|conn| conn.awareness_client_id)
+        else {
+            return;
+        };
+
+        let current = self.awareness.read().await
+            .get(document_id)
+            .and_then(|room| room.get(&client_id))
+            .map(|entry| (entry.clock, entry.state.clone()));
+        let state = serde_json::to_value(presence).unwrap_or_default();
+        let clock = match current {
+            // Unchanged presence is a heartbeat: renew it without a broadcast
+            Some((clock, current)) if current == state => clock,
+            Some((clock, _)) => clock + 1,
+            None => 0,
+        };
+        self.apply_awareness(document_id, connection_id, vec![AwarenessState { client_id, clock, state: Some(state) }]).await;
+    }
+
+    /// Drop entries that haven't been renewed within `timeout`.
+    pub async fn expire_awareness(&self, timeout: Duration) {
+        let rooms: Vec<String> = self.awareness.read().await.keys().cloned().collect();
+        for document_id in rooms {
+            self.remove_awareness(&document_id, |entry| entry.renewed_at.elapsed() >= timeout).await;
+        }
+    }
+
+    /// Remove the matching entries of a room and tell the room they are gone.
+    async fn remove_awareness(&self, document_id: &str, matches: impl Fn(&AwarenessEntry) -> bool) {
+        let removed: Vec<AwarenessState> = {
+            let mut awareness = self.awareness.write().await;
+            let Some(room) = awareness.get_mut(document_id) else { return };
+            let expired: Vec<u64> = room.iter()
+                .filter(|(_, entry)| matches(entry))
+                .map(|(&client_id, _)| client_id)
+                .collect();
+            let removed = expired.into_iter().filter_map(|client_id| {
+                room.remove(&client_id).map(|entry| AwarenessState {
+                    client_id,
+                    clock: entry.clock + 1,
+                    state: None,
+                })
+            }).collect();
+            if room.is_empty() {
+                awareness.remove(document_id);
+            }
+            removed
+        };
+
+        if !removed.is_empty() {
+            self.broadcast_awareness(document_id, removed, None).await;
         }
     }
 }
@@ -158,56 +760,309 @@ impl Default for WebSocketManager {
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
     Path(document_id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
     State(state): State<AppState>,
-) -> impl IntoResponse {
+    headers: HeaderMap,
+) -> Response {
     info!("WebSocket upgrade request for document: {}", document_id);
-    ws.on_upgrade(|socket| handle_socket(socket, document_id, state))
+
+    if state.ws_manager.is_shutting_down() {
+        return AppError::ServiceUnavailable("Server is restarting".to_string()).into_response();
+    }
+
+    let offered_protocols = offered_subprotocols(&headers);
+    let token_protocol = offered_protocols.iter().find(|p| p.starts_with(TOKEN_SUBPROTOCOL_PREFIX));
+    let identity = match authenticate_connection(&state, &params, &headers, token_protocol) {
+        Ok(identity) => identity,
+        Err(e) => return e.into_response(),
+    };
+
+    // Clients that name no protocol are told apart by their first frame
+    let protocol = match requested_protocol(&offered_protocols, &params) {
+        Ok(protocol) => protocol,
+        Err(e) => return e.into_response(),
+    };
+    let ip_address = extract_client_ip_from_headers(&headers);
+
+    // Browsers fail the handshake unless one of the offered subprotocols is
+    // selected, so a token-only offer gets its own entry echoed back
+    let selected_protocol = protocol
+        .map(WireProtocol::subprotocol)
+        .filter(|subprotocol| offered_protocols.iter().any(|p| p == subprotocol))
+        .or(token_protocol.map(String::as_str))
+        .and_then(|p| HeaderValue::from_str(p).ok());
+
+    // Frames over the limit close the connection with 1009 (Message Too Big)
+    let limits = axum_tws::Limits::default().max_payload_len(Some(state.ws_manager.config().max_frame_bytes));
+    let mut response = ws.limits(limits)
+        .on_upgrade(move |socket| handle_socket(socket, document_id, identity, state, protocol, ip_address));
+    if let Some(selected_protocol) = selected_protocol {
+        response.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, selected_protocol);
+    }
+    response.into_response()
 }
 
-async fn handle_socket(socket: WebSocket, document_id: String, state: AppState) {
+/// Entries of the `Sec-WebSocket-Protocol` header a client offered.
+pub(crate) fn offered_subprotocols(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(',').map(|p| p.trim().to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// The protocol a client asked for by subprotocol or, failing that, with
+/// `PROTOCOL_PARAM`. `None` leaves it to the client's first frame.
+fn requested_protocol(
+    offered_protocols: &[String],
+    params: &HashMap<String, String>,
+) -> Result<Option<WireProtocol>, AppError> {
+    let offered = [WireProtocol::Yjs, WireProtocol::Envelope, WireProtocol::Json]
+        .into_iter()
+        .find(|protocol| offered_protocols.iter().any(|p| p == protocol.subprotocol()));
+    if offered.is_some() {
+        return Ok(offered);
+    }
+    match params.get(PROTOCOL_PARAM).map(String::as_str) {
+        None => Ok(None),
+        Some("json") => Ok(Some(WireProtocol::Json)),
+        Some("envelope") => Ok(Some(WireProtocol::Envelope)),
+        Some("yjs") => Ok(Some(WireProtocol::Yjs)),
+        Some(other) => Err(AppError::ValidationError(format!("Unknown protocol: {}", other))),
+    }
+}
+
+/// Who a connection acts as, and whether it may edit.
+pub(crate) struct ConnectionIdentity {
+    pub(crate) user_id: String,
+    /// Asked for with `SPECTATOR_PARAM`, or forced by a read-only token
+    pub(crate) spectator: bool,
+    /// No token was presented, so `user_id` is made up for this connection
+    pub(crate) anonymous: bool,
+}
+
+/// Who an upgrade request acts as: the account of the JWT it presents
+/// (see `TOKEN_SUBPROTOCOL_PREFIX`), or a random ID if anonymous access is allowed.
+/// The REST endpoints that act for a user authenticate the same way.
+pub(crate) fn authenticate_connection(
+    state: &AppState,
+    params: &HashMap<String, String>,
+    headers: &HeaderMap,
+    token_protocol: Option<&String>,
+) -> Result<ConnectionIdentity, AppError> {
+    let token = params.get("token").cloned()
+        .or_else(|| token_protocol.map(|p| p[TOKEN_SUBPROTOCOL_PREFIX.len()..].to_string()))
+        .or_else(|| extract_cookie(headers, TOKEN_COOKIE))
+        // Not something browsers can set on a WebSocket, but REST clients can
+        .or_else(|| {
+            headers.get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(str::to_string)
+        });
+    let spectator = params.get(SPECTATOR_PARAM).is_some_and(|value| value == "true");
+    match token {
+        // A token that doesn't check out is refused even if anonymous access is allowed
+        Some(token) => {
+            let user = authenticate_token(&token)?;
+            Ok(ConnectionIdentity {
+                user_id: user.user_id.to_string(),
+                spectator: spectator || user.is_read_only(),
+                anonymous: false,
+            })
+        }
+        None if state.ws_manager.config().allow_anonymous => {
+            Ok(ConnectionIdentity { user_id: Uuid::new_v4().to_string(), spectator, anonymous: true })
+        }
+        None => Err(AppError::AuthenticationError("Missing authentication token".to_string())),
+    }
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    document_id: String,
+    identity: ConnectionIdentity,
+    state: AppState,
+    protocol: Option<WireProtocol>,
+    ip_address: String,
+) {
+    let ConnectionIdentity { user_id, spectator, anonymous } = identity;
     let (mut sender, mut receiver) = socket.split();
     
-    // Generate unique IDs for this connection
-    let user_id = Uuid::new_v4().to_string();
+    // Generate a unique ID for this connection
     let connection_id = Uuid::new_v4().to_string();
     info!("WebSocket connection established for document {} by user {} with connection {}", document_id, user_id, connection_id);
 
+    // Without a requested protocol, the first frame tells us what the client speaks:
+    // y-websocket opens with a binary sync step 1, JSON clients with `JoinDocument`
+    let mut first_frame = None;
+    let protocol = match protocol {
+        Some(protocol) => protocol,
+        None => match tokio::time::timeout(PROTOCOL_DETECTION_TIMEOUT, receiver.next()).await {
+            Ok(Some(Ok(msg))) => {
+                let protocol = if msg.is_binary() { WireProtocol::Yjs } else { WireProtocol::Json };
+                first_frame = Some(Ok(msg));
+                protocol
+            }
+            Ok(Some(Err(e))) => {
+                error!("WebSocket error: {}", e);
+                // Such as the close frame an oversized frame is answered with
+                let _ = sender.flush().await;
+                return;
+            }
+            Ok(None) => return,
+            Err(_) => WireProtocol::Json,
+        },
+    };
+    debug!("Connection {} speaks {:?}", connection_id, protocol);
+
     // Join the document room
-    let mut rx = state.ws_manager.join_document(document_id.clone(), user_id.clone(), connection_id.clone()).await;
+    let rx = if spectator {
+        state.ws_manager.spectate_document(document_id.clone(), user_id.clone(), connection_id.clone()).await
+    } else {
+        state.ws_manager.join_document(document_id.clone(), user_id.clone(), connection_id.clone()).await
+    };
+    let rx = match rx {
+        Ok(rx) => rx,
+        // Over a connection cap: 1013 (Try Again Later)
+        Err(e) => {
+            warn!("Refusing connection {} to document {}: {}", connection_id, document_id, e);
+            let frames = error_close_frames(protocol, &e, axum_tws::CloseCode::SERVICE_OVERLOAD);
+            if let Err(e) = sender.send_all(&mut futures_util::stream::iter(frames).map(Ok)).await {
+                error!("Failed to send WebSocket message: {}", e);
+            }
+            return;
+        }
+    };
+
+    // Give the client a starting point before any room broadcast reaches it.
+    // We are already subscribed, so nothing published after this snapshot is missed.
+    for frame in initial_frames(&state, &document_id, protocol).await {
+        if let Err(e) = sender.send(frame).await {
+            error!("Failed to send WebSocket message: {}", e);
+            state.ws_manager.leave_document(&document_id, &user_id, &connection_id).await;
+            return;
+        }
+    }
+
+    // Replies addressed to this connection only (e.g. sync step 2 or errors)
+    let (reply_tx, reply_rx) = mpsc::channel::<axum_tws::Message>(100);
+
+    let ping_interval = state.ws_manager.config().ping_interval();
+    let idle_timeout = state.ws_manager.config().idle_timeout();
 
     // Handle incoming messages
-    let mut recv_task = tokio::spawn(async move {
-        while let Some(msg) = receiver.next().await {
-            match msg {
-                Ok(msg) => {
-                    if msg.is_text() {
-                        // For now, just log the message
-                        info!("Received WebSocket message: {:?}", msg);
+    let mut recv_task = {
+        let state = state.clone();
+        let document_id = document_id.clone();
+        let user_id = user_id.clone();
+        let connection_id = connection_id.clone();
+        let mut incoming = futures_util::stream::iter(first_frame).chain(receiver);
+        let mut limiter = state.ws_manager.connection_limiter();
+        tokio::spawn(async move {
+            let client = ClientContext {
+                state: &state,
+                document_id: &document_id,
+                user_id: &user_id,
+                connection_id: &connection_id,
+                ip_address: &ip_address,
+                multiplexed: false,
+                spectator,
+                anonymous,
+            };
+            loop {
+                // Any frame counts as a sign of life, including pongs to our pings
+                let msg = match tokio::time::timeout(idle_timeout, incoming.next()).await {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => break,
+                    Err(_) => {
+                        info!("Evicting connection {} after {:?} without traffic", connection_id, idle_timeout);
+                        break;
+                    }
+                };
+                match msg {
+                    Ok(msg) => {
+                        // Control frames don't count against the rate limits
+                        let verdict = if msg.is_text() || msg.is_binary() {
+                            state.ws_manager.check_rate(&mut limiter, Some(&document_id))
+                        } else {
+                            Verdict::Allow
+                        };
+                        if verdict == Verdict::Disconnect {
+                            warn!("Disconnecting connection {} for going over its rate limit", connection_id);
+                            let frames = error_close_frames(protocol, &AppError::RateLimitExceeded, axum_tws::CloseCode::POLICY_VIOLATION);
+                            for frame in frames {
+                                let _ = reply_tx.send(frame).await;
+                            }
+                            break;
+                        }
+
+                        let mut request_id = None;
+                        let result = match protocol {
+                            // Yjs clients have no error message, so they only catch up on
+                            // what was dropped once they reconnect
+                            WireProtocol::Yjs if msg.is_binary() && verdict == Verdict::Throttle => {
+                                Err(AppError::RateLimitExceeded)
+                            }
+                            WireProtocol::Yjs if msg.is_binary() => {
+                                handle_sync_frame(&client, msg.as_payload(), &reply_tx).await
+                            }
+                            WireProtocol::Json | WireProtocol::Envelope if msg.is_text() => {
+                                match decode_json_frame(msg.as_text().unwrap_or_default(), protocol, &mut request_id) {
+                                    // Still decoded, so that the error goes to the right request
+                                    Ok(_) if verdict == Verdict::Throttle => Err(AppError::RateLimitExceeded),
+                                    Ok(envelope) => {
+                                        handle_json_message(&client, envelope.message, protocol, request_id.as_deref(), &reply_tx).await
+                                    }
+                                    Err(e) => Err(e),
+                                }
+                            }
+                            WireProtocol::Json | WireProtocol::Envelope if msg.is_binary() => Err(AppError::ValidationError(
+                                format!("Binary frames require the {} subprotocol", YJS_SUBPROTOCOL),
+                            )),
+                            // Control frames are answered by the WebSocket layer itself
+                            _ => Ok(()),
+                        };
+
+                        let reply = match result {
+                            Ok(()) if request_id.is_some() => Some(WebSocketMessage::Ack),
+                            Ok(()) => None,
+                            Err(e) => {
+                                warn!("Failed to handle WebSocket message for document {}: {}", document_id, e);
+                                Some(WebSocketMessage::Error { message: e.public_message() })
+                            }
+                        };
+                        if let Some(frame) = reply.and_then(|reply| reply.to_reply_frame(protocol, request_id.as_deref())) {
+                            let _ = reply_tx.send(frame).await;
+                        }
+                    }
+                    Err(e) => {
+                        error!("WebSocket error: {}", e);
+                        break;
                     }
-                }
-                Err(e) => {
-                    error!("WebSocket error: {}", e);
-                    break;
                 }
             }
-        }
-    });
+        })
+    };
 
     // Handle outgoing messages
-    let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            let text = serde_json::to_string(&msg).unwrap();
-            if let Err(e) = sender.send(axum_tws::Message::text(text)).await {
-                error!("Failed to send WebSocket message: {}", e);
-                break;
-            }
-        }
-    });
+    let frames = ConnectionFrames {
+        state: state.clone(),
+        document_id: document_id.clone(),
+        connection_id: connection_id.clone(),
+        protocol,
+        rx,
+        reply_rx,
+    };
+    let mut send_task = tokio::spawn(send_frames(sender, frames, protocol, ping_interval, state.ws_manager.shutdown_signal()));
 
     // Wait for either task to complete
     tokio::select! {
         _ = (&mut recv_task) => {
-            send_task.abort();
+            // Give the send task a moment to get its last frames out
+            if tokio::time::timeout(CLOSE_TIMEOUT, &mut send_task).await.is_err() {
+                send_task.abort();
+            }
         }
         _ = (&mut send_task) => {
             recv_task.abort();
@@ -219,18 +1074,475 @@ async fn handle_socket(socket: WebSocket, document_id: String, state: AppState)
     info!("WebSocket connection closed for document {} by user {} with connection {}", document_id, user_id, connection_id);
 }
 
+/// Where a connection's outgoing frames come from.
+pub(crate) trait FrameSource: Send {
+    /// The next frames to send, or `None` once there are no more, e.g.
+    /// because the receiving side is done.
+    async fn next_frames(&mut self) -> Option<Vec<axum_tws::Message>>;
+}
+
+impl FrameSource for mpsc::Receiver<axum_tws::Message> {
+    async fn next_frames(&mut self) -> Option<Vec<axum_tws::Message>> {
+        self.recv().await.map(|frame| vec![frame])
+    }
+}
+
+/// Outgoing frames of a `/ws/doc` connection: its document room's messages
+/// from other connections, and replies to its own.
+struct ConnectionFrames {
+    state: AppState,
+    document_id: String,
+    connection_id: String,
+    protocol: WireProtocol,
+    rx: broadcast::Receiver<RoomMessage>,
+    reply_rx: mpsc::Receiver<axum_tws::Message>,
+}
+
+impl FrameSource for ConnectionFrames {
+    async fn next_frames(&mut self) -> Option<Vec<axum_tws::Message>> {
+        loop {
+            tokio::select! {
+                msg = self.rx.recv() => match msg {
+                    Ok(msg) if msg.origin.as_deref() == Some(self.connection_id.as_str()) => continue,
+                    Ok(msg) => {
+                        if let Some(frame) = msg.message.to_frame(self.protocol) {
+                            return Some(vec![frame]);
+                        }
+                    }
+                    // The receiver has already skipped ahead to the oldest message still
+                    // buffered, so replacing what was dropped with the full state is enough
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Connection {} lagged {} messages behind, resyncing", self.connection_id, skipped);
+                        return Some(resync_frames(&self.state, &self.document_id, self.protocol).await);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                },
+                frame = self.reply_rx.recv() => return frame.map(|frame| vec![frame]),
+            }
+        }
+    }
+}
+
+/// Send a connection's frames as `frames` yields them, pinging every
+/// `ping_interval`, until it runs dry, a close frame goes out or `shutdown`
+/// resolves, which tells the client the server is restarting.
+pub(crate) async fn send_frames(
+    mut sender: SplitSink<WebSocket, axum_tws::Message>,
+    mut frames: impl FrameSource,
+    protocol: WireProtocol,
+    ping_interval: Duration,
+    shutdown: impl Future<Output = ()>,
+) {
+    let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + ping_interval, ping_interval);
+    let mut shutdown = std::pin::pin!(shutdown);
+    loop {
+        let batch = tokio::select! {
+            batch = frames.next_frames() => match batch {
+                Some(batch) => batch,
+                // Flush what is still queued, such as the close frame the
+                // WebSocket layer answers an oversized frame with
+                None => {
+                    if let Err(e) = sender.flush().await {
+                        error!("Failed to send WebSocket message: {}", e);
+                    }
+                    break;
+                }
+            },
+            _ = ping.tick() => vec![axum_tws::Message::ping(&b""[..])],
+            _ = &mut shutdown => {
+                let frames = WebSocketMessage::ServerRestarting.to_frame(protocol).into_iter()
+                    .chain([axum_tws::Message::close(Some(axum_tws::CloseCode::SERVICE_RESTART), "Server restarting")]);
+                if let Err(e) = sender.send_all(&mut futures_util::stream::iter(frames).map(Ok)).await {
+                    error!("Failed to send WebSocket message: {}", e);
+                }
+                break;
+            }
+        };
+        let closing = batch.iter().any(|frame| frame.is_close());
+        if let Err(e) = sender.send_all(&mut futures_util::stream::iter(batch).map(Ok)).await {
+            error!("Failed to send WebSocket message: {}", e);
+            break;
+        }
+        if closing {
+            break;
+        }
+    }
+}
+
+/// Keeps a connection that isn't a socket of its own, such as an event stream
+/// or a long poll, in its document room, and takes it out once dropped, i.e.
+/// once the request is done or the client has gone away.
+pub(crate) struct RoomGuard {
+    pub(crate) state: AppState,
+    pub(crate) document_id: String,
+    pub(crate) connection_id: String,
+    /// Whom to announce as leaving, for a connection that joined; one that
+    /// only watched the room (see `WebSocketManager::watch_document`) leaves quietly
+    user_id: Option<String>,
+}
+
+impl RoomGuard {
+    /// For a connection that joined with `join_document` or `spectate_document`.
+    pub(crate) fn joined(state: AppState, document_id: String, user_id: String, connection_id: String) -> Self {
+        Self { state, document_id, connection_id, user_id: Some(user_id) }
+    }
+
+    /// For a connection that joined with `watch_document`.
+    pub(crate) fn watching(state: AppState, document_id: String, connection_id: String) -> Self {
+        Self { state, document_id, connection_id, user_id: None }
+    }
+}
+
+impl Drop for RoomGuard {
+    fn drop(&mut self) {
+        let state = self.state.clone();
+        let document_id = std::mem::take(&mut self.document_id);
+        let connection_id = std::mem::take(&mut self.connection_id);
+        let user_id = self.user_id.take();
+        tokio::spawn(async move {
+            match user_id {
+                Some(user_id) => state.ws_manager.leave_document(&document_id, &user_id, &connection_id).await,
+                None => state.ws_manager.unwatch_document(&document_id, &connection_id).await,
+            }
+        });
+    }
+}
+
+/// An `Error` (for protocols that have one) followed by a close frame with
+/// the same message as its reason.
+pub(crate) fn error_close_frames(protocol: WireProtocol, error: &AppError, code: axum_tws::CloseCode) -> Vec<axum_tws::Message> {
+    let message = error.public_message();
+    let close = axum_tws::Message::close(Some(code), &message);
+    WebSocketMessage::Error { message }.to_frame(protocol).into_iter()
+        .chain([close])
+        .collect()
+}
+
+/// Frames sent to a client that just joined: its starting point in the
+/// document (see `initial_sync_frame`) and who else is in the room.
+async fn initial_frames(state: &AppState, document_id: &str, protocol: WireProtocol) -> Vec<axum_tws::Message> {
+    let sync = initial_sync_frame(state, document_id, protocol).await;
+    sync.into_iter().chain(awareness_frame(state, document_id, protocol).await).collect()
+}
+
+/// The current awareness states of a room, if anyone in it has any.
+async fn awareness_frame(state: &AppState, document_id: &str, protocol: WireProtocol) -> Option<axum_tws::Message> {
+    let states = state.ws_manager.awareness_states(document_id).await;
+    if states.is_empty() {
+        return None;
+    }
+    WebSocketMessage::Awareness { states }.to_frame(protocol)
+}
+
+/// Frames that bring a client which missed room broadcasts back in step: the
+/// whole document (a `DocumentState`, or the full update as a sync step 2)
+/// and the current awareness states.
+async fn resync_frames(state: &AppState, document_id: &str, protocol: WireProtocol) -> Vec<axum_tws::Message> {
+    let sync = match protocol {
+        WireProtocol::Json | WireProtocol::Envelope => initial_sync_frame(state, document_id, protocol).await,
+        WireProtocol::Yjs => match state.database.get_document_crdt_diff(document_id, &StateVector::default()).await {
+            Ok(update) => Some(sync_frame(SyncProtocolMessage::Sync(SyncMessage::SyncStep2(update)))),
+            Err(e) => {
+                warn!("Cannot resync document {}: {}", document_id, e);
+                None
+            }
+        },
+    };
+    sync.into_iter().chain(awareness_frame(state, document_id, protocol).await).collect()
+}
+
+/// First frame sent to a client that just joined: the current `DocumentState`
+/// for JSON clients, and a sync step 1 for Yjs clients, which, like with the
+/// reference y-websocket server, asks them for anything the server is missing.
+async fn initial_sync_frame(state: &AppState, document_id: &str, protocol: WireProtocol) -> Option<axum_tws::Message> {
+    match protocol {
+        WireProtocol::Json | WireProtocol::Envelope => {
+            let message = match state.database.get_document_crdt_state(document_id).await {
+                Ok(state) => WebSocketMessage::DocumentState { state },
+                Err(e) => WebSocketMessage::Error { message: e.public_message() },
+            };
+            message.to_frame(protocol)
+        }
+        WireProtocol::Yjs => match state.database.get_document_crdt_state_vector(document_id).await {
+            Ok(state_vector) => Some(sync_frame(SyncProtocolMessage::Sync(SyncMessage::SyncStep1(state_vector)))),
+            Err(e) => {
+                warn!("Cannot sync document {}: {}", document_id, e);
+                None
+            }
+        },
+    }
+}
+
+/// Who is on the other end of a socket, for the frame handlers.
+pub(crate) struct ClientContext<'a> {
+    pub(crate) state: &'a AppState,
+    pub(crate) document_id: &'a str,
+    pub(crate) user_id: &'a str,
+    pub(crate) connection_id: &'a str,
+    pub(crate) ip_address: &'a str,
+    /// Replies are tagged with `document_id`, as on the multiplexed endpoint
+    pub(crate) multiplexed: bool,
+    /// Edits are refused, and presence ignored
+    pub(crate) spectator: bool,
+    /// `user_id` is made up for this connection, so no one can undo its
+    /// edits once it is gone
+    pub(crate) anonymous: bool,
+}
+
+/// Decode a JSON text frame: a bare `WebSocketMessage` (returned as a
+/// version 1 envelope), or an `Envelope` whose ID is stored in `request_id`
+/// as soon as it is known, so that even a request that is refused can be
+/// answered under its ID.
+pub(crate) fn decode_json_frame(text: &str, protocol: WireProtocol, request_id: &mut Option<String>) -> Result<Envelope, AppError> {
+    let malformed = |e: serde_json::Error| AppError::ValidationError(format!("Malformed message: {}", e));
+    if protocol != WireProtocol::Envelope {
+        let message = serde_json::from_str(text).map_err(malformed)?;
+        return Ok(Envelope { v: 1, id: None, document_id: None, message });
+    }
+
+    let envelope: Envelope<serde_json::Value> = serde_json::from_str(text).map_err(malformed)?;
+    *request_id = envelope.id;
+    if envelope.v != PROTOCOL_VERSION {
+        return Err(AppError::ValidationError(format!(
+            "Unsupported protocol version {}, this server speaks version {}", envelope.v, PROTOCOL_VERSION
+        )));
+    }
+    Ok(Envelope {
+        v: envelope.v,
+        id: request_id.clone(),
+        document_id: envelope.document_id,
+        message: serde_json::from_value(envelope.message).map_err(malformed)?,
+    })
+}
+
+/// The error a spectator's edit is refused with.
+pub(crate) fn spectator_edit() -> AppError {
+    AppError::AuthorizationError("Spectators can't edit this document".to_string())
+}
+
+/// Handle one `WebSocketMessage` from a JSON client. Replies go to the
+/// request with ID `request_id`, if it has one.
+pub(crate) async fn handle_json_message(
+    client: &ClientContext<'_>,
+    message: WebSocketMessage,
+    protocol: WireProtocol,
+    request_id: Option<&str>,
+    replies: &mpsc::Sender<axum_tws::Message>,
+) -> Result<(), AppError> {
+    match message {
+        WebSocketMessage::JoinDocument { document_id, .. } => {
+            // The room is chosen by the URL; joining is only a handshake
+            if document_id != client.document_id {
+                return Err(AppError::ValidationError(format!(
+                    "This connection is bound to document {}", client.document_id
+                )));
+            }
+            Ok(())
+        }
+        WebSocketMessage::UpdateDocument { .. } | WebSocketMessage::Undo | WebSocketMessage::Redo if client.spectator => {
+            Err(spectator_edit())
+        }
+        // Spectators aren't announced to the room, so they don't show up in its presence either
+        WebSocketMessage::UpdatePresence { .. } if client.spectator => Ok(()),
+        WebSocketMessage::UpdateDocument { content, .. } => {
+            let request = UpdateDocumentRequest { content };
+            request.validate().map_err(|e| {
+                AppError::ValidationError(format!("Validation failed: {}", e))
+            })?;
+
+            let (_, update) = client.state.database
+                .update_document_as(client.document_id, &request.content, client.user_id, client.ip_address)
+                .await?;
+            // Periodic saves of unchanged content have nothing to pass on
+            if !update.is_empty() {
+                client.state.ws_manager
+                    .broadcast_update(client.document_id, update, Some(client.connection_id))
+                    .await;
+            }
+            Ok(())
+        }
+        WebSocketMessage::UpdatePresence { presence } => {
+            client.state.ws_manager
+                .update_presence(client.document_id, client.connection_id, &presence)
+                .await;
+            Ok(())
+        }
+        WebSocketMessage::Undo | WebSocketMessage::Redo => {
+            let result = if matches!(message, WebSocketMessage::Undo) {
+                client.state.database.undo_crdt_update(client.document_id, client.user_id).await?
+            } else {
+                client.state.database.redo_crdt_update(client.document_id, client.user_id).await?
+            };
+            // The sender's copy changes too, so nobody is skipped
+            if let Some(update) = result.update {
+                client.state.ws_manager.broadcast_update(client.document_id, update, None).await;
+            }
+            Ok(())
+        }
+        WebSocketMessage::RequestDiff { state_vector, since_version } => {
+            let request = DiffRequest { state_vector, since_version };
+            let diff = client.state.database.get_document_crdt_delta(client.document_id, &request).await?;
+            let reply = WebSocketMessage::DocumentDiff { diff };
+            let frame = if client.multiplexed {
+                reply.to_envelope_frame(request_id, Some(client.document_id))
+            } else {
+                reply.to_reply_frame(protocol, request_id)
+            };
+            if let Some(frame) = frame {
+                let _ = replies.send(frame).await;
+            }
+            Ok(())
+        }
+        _ => Err(AppError::ValidationError(
+            "Only JoinDocument, UpdateDocument, UpdatePresence, Undo, Redo and RequestDiff can be sent by clients".to_string(),
+        )),
+    }
+}
+
+/// Handle one binary y-protocols frame, which may carry several messages.
+async fn handle_sync_frame(
+    client: &ClientContext<'_>,
+    data: &[u8],
+    replies: &mpsc::Sender<axum_tws::Message>,
+) -> Result<(), AppError> {
+    let ClientContext { state, document_id, user_id, connection_id, spectator, anonymous, .. } = *client;
+    let mut decoder = DecoderV1::new(Cursor::new(data));
+    for message in MessageReader::new(&mut decoder) {
+        let message = message.map_err(|e| AppError::ValidationError(format!("Malformed sync message: {}", e)))?;
+        match message {
+            SyncProtocolMessage::Sync(SyncMessage::SyncStep1(state_vector)) => {
+                let update = state.database.get_document_crdt_diff(document_id, &state_vector).await?;
+                let _ = replies.send(sync_frame(SyncProtocolMessage::Sync(SyncMessage::SyncStep2(update)))).await;
+            }
+            SyncProtocolMessage::Sync(SyncMessage::SyncStep2(update))
+            | SyncProtocolMessage::Sync(SyncMessage::Update(update)) => {
+                // A client with nothing new answers our sync step 1 with an empty update
+                if update.as_slice() == Update::EMPTY_V1 {
+                    continue;
+                }
+                if spectator {
+                    return Err(spectator_edit());
+                }
+                let update = DocumentUpdate {
+                    content: String::new(),
+                    user_id: user_id.to_string(),
+                    timestamp: chrono::Utc::now().timestamp(),
+                    update,
+                    version: None,
+                    logged_as: None,
+                };
+                // Sync clients can only undo over REST, which an anonymous one can't do as itself
+                let applied = state.database.apply_crdt_update(document_id, &update, !anonymous).await?;
+                state.ws_manager.broadcast_update(document_id, applied, Some(connection_id)).await;
+            }
+            SyncProtocolMessage::Awareness(_) if spectator => {}
+            SyncProtocolMessage::Awareness(update) => {
+                let states = AwarenessState::from_update(update)?;
+                state.ws_manager.apply_awareness(document_id, connection_id, states).await;
+            }
+            SyncProtocolMessage::AwarenessQuery => {
+                let states = state.ws_manager.awareness_states(document_id).await;
+                let update = AwarenessState::to_update(&states);
+                let _ = replies.send(sync_frame(SyncProtocolMessage::Awareness(update))).await;
+            }
+            other => debug!("Ignoring unsupported sync message: {:?}", other),
+        }
+    }
+    Ok(())
+}
+
+/// Deliver the room messages other instances relay to us to the local rooms,
+/// first merging relayed edits into our copy of the document if it's loaded,
+/// so this instance serves them too. Does nothing in single-node mode.
+///
+/// Whenever relayed messages may have been lost, every loaded document
+/// catches up with the update log instead, and what it was missing is sent
+/// to its room like any other update.
+pub fn spawn_relay_listener(state: AppState) -> Option<tokio::task::JoinHandle<()>> {
+    let mut incoming = state.ws_manager.relay().subscribe()?;
+    let mut caught_up = state.database.documents.subscribe_catch_ups();
+    Some(tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                event = incoming.recv() => event,
+                caught_up = caught_up.recv() => {
+                    match caught_up {
+                        Ok(CaughtUp { document_id, update }) => {
+                            state.ws_manager.deliver(&document_id, WebSocketMessage::DocumentUpdated { update }.into()).await;
+                        }
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            warn!("Missed {} catch-ups to send to local rooms", skipped);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                    continue;
+                }
+            };
+            let mut relayed = match event {
+                Ok(RelayEvent::Message(relayed)) => relayed,
+                Ok(RelayEvent::Missed) => {
+                    warn!("Relayed room messages were lost, catching up with the update log");
+                    catch_up_logged(&state).await;
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Missed {} relayed room messages, catching up with the update log", skipped);
+                    catch_up_logged(&state).await;
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            if let WebSocketMessage::DocumentUpdated { update } = &mut relayed.message.message {
+                update.logged_as = relayed.logged_as;
+                // Versions count the updates each instance applied, so only ours mean anything here
+                update.version = match state.database.merge_crdt_update(&relayed.document_id, update).await {
+                    Ok(version) => version,
+                    Err(e) => {
+                        warn!("Cannot merge relayed update into document {}: {}", relayed.document_id, e);
+                        None
+                    }
+                };
+            }
+            state.ws_manager.deliver(&relayed.document_id, relayed.message).await;
+        }
+    }))
+}
+
+async fn catch_up_logged(state: &AppState) {
+    if let Err(e) = state.database.catch_up().await {
+        warn!("Cannot catch up with the update log: {}", e);
+    }
+}
+
+// HTTP endpoint that returns how many rooms and connections are live
+pub async fn websocket_metrics_handler(
+    State(state): State<AppState>,
+) -> axum::Json<WebSocketMetrics> {
+    axum::Json(state.ws_manager.metrics().await)
+}
+
 // HTTP endpoint that returns WebSocket info (for debugging)
 pub async fn websocket_info_handler(
     Path(document_id): Path<String>,
     State(state): State<AppState>,
 ) -> axum::Json<serde_json::Value> {
-    let active_users_count = state.ws_manager.get_active_users_count(&document_id).await;
+    let active_users = state.ws_manager.get_active_users_count(&document_id).await;
+    let connections = state.ws_manager.connection_counts(&document_id).await;
+    let config = state.ws_manager.config();
     
     axum::Json(serde_json::json!({
         "message": "WebSocket endpoint",
         "document_id": document_id,
         "endpoint": format!("/ws/doc/{}", document_id),
         "protocol": "ws:// or wss://",
-        "active_users_count": active_users_count
+        "active_users_count": active_users.editors,
+        "spectators_count": active_users.spectators,
+        "connections_count": connections.document,
+        "total_connections_count": connections.total,
+        "connection_caps": {
+            "per_document": config.max_connections_per_document,
+            "per_user": config.max_connections_per_user,
+            "total": config.max_total_connections
+        }
     }))
 } 
\ No newline at end of file
@@ -0,0 +1,183 @@
+use collaborative_docs_rs::crdt::{CRDTDocument, DiffRequest, DocumentUpdate};
+
+fn replica_of(doc: &CRDTDocument, id: &str) -> CRDTDocument {
+    let mut replica = CRDTDocument::new(id.to_string());
+    replica.apply_update(&DocumentUpdate {
+        content: String::new(),
+        user_id: "sync".to_string(),
+        timestamp: 0,
+        update: doc.encode_state_as_update(),
+        version: None,
+        logged_as: None,
+    }).unwrap();
+    replica
+}
+
+#[test]
+fn test_update_content_produces_binary_update() {
+    let mut doc = CRDTDocument::new("doc-1".to_string());
+    let update = doc.update_content("Hello", "user1");
+
+    assert_eq!(update.content, "Hello");
+    assert_eq!(update.user_id, "user1");
+    assert!(!update.update.is_empty());
+    assert_eq!(doc.get_state().version, 1);
+}
+
+#[test]
+fn test_unchanged_content_is_not_a_new_version() {
+    let mut doc = CRDTDocument::from_existing("doc-9".to_string(), "Hello".to_string());
+    let update = doc.update_content("Hello", "user1");
+
+    assert!(update.is_empty());
+    assert_eq!(update.version, Some(0));
+    assert_eq!(doc.get_state().version, 0);
+    assert!(doc.get_diff(0).is_some());
+}
+
+#[test]
+fn test_concurrent_edits_converge() {
+    let mut alice = CRDTDocument::from_existing("doc-2".to_string(), "Hello world".to_string());
+    let mut bob = replica_of(&alice, "doc-2");
+    assert_eq!(bob.get_content(), "Hello world");
+
+    // Both users edit different parts of the same base text at once
+    let from_alice = alice.update_content("Hello brave world", "alice");
+    let from_bob = bob.update_content("Hello world!", "bob");
+
+    alice.apply_update(&from_bob).unwrap();
+    bob.apply_update(&from_alice).unwrap();
+
+    assert_eq!(alice.get_content(), "Hello brave world!");
+    assert_eq!(alice.get_content(), bob.get_content());
+}
+
+#[test]
+fn test_content_only_update_is_diffed() {
+    let mut doc = CRDTDocument::from_existing("doc-3".to_string(), "naïve café".to_string());
+    doc.apply_update(&DocumentUpdate {
+        content: "naïve café ☕".to_string(),
+        user_id: "user1".to_string(),
+        timestamp: 0,
+        update: Vec::new(),
+        version: None,
+        logged_as: None,
+    }).unwrap();
+
+    assert_eq!(doc.get_content(), "naïve café ☕");
+    assert_eq!(doc.get_state().version, 1);
+}
+
+#[test]
+fn test_invalid_binary_update_is_rejected() {
+    let mut doc = CRDTDocument::new("doc-4".to_string());
+
+    let result = doc.apply_update(&DocumentUpdate {
+        content: String::new(),
+        user_id: "user1".to_string(),
+        timestamp: 0,
+        update: vec![0xff, 0xff, 0xff],
+        version: None,
+        logged_as: None,
+    });
+    assert!(result.is_err());
+    assert_eq!(doc.get_state().version, 0);
+}
+
+#[test]
+fn test_diff_since_version_only_carries_new_edits() {
+    let mut server = CRDTDocument::from_existing("doc-5".to_string(), "x".repeat(1000));
+    let mut client = replica_of(&server, "doc-5");
+    let seen = server.get_state().version;
+
+    server.update_content(&format!("{}!", "x".repeat(1000)), "alice");
+
+    let delta = server.get_diff(seen).unwrap();
+    assert!(delta.len() < server.encode_state_as_update().len());
+    client.apply_update(&DocumentUpdate {
+        content: String::new(),
+        user_id: "sync".to_string(),
+        timestamp: 0,
+        update: delta,
+        version: None,
+        logged_as: None,
+    }).unwrap();
+    assert_eq!(client.get_content(), server.get_content());
+
+    // Unknown versions fall back to the full state
+    assert!(server.get_diff(seen + 10).is_none());
+    let diff = server.diff(&DiffRequest { since_version: Some(seen + 10), ..Default::default() }).unwrap();
+    assert_eq!(diff.update, server.encode_state_as_update());
+    assert_eq!(diff.version, server.get_state().version);
+}
+
+#[test]
+fn test_diff_rejects_invalid_state_vector() {
+    let doc = CRDTDocument::new("doc-6".to_string());
+    let request = DiffRequest { state_vector: vec![0xff, 0xff], since_version: None };
+    assert!(doc.diff(&request).is_err());
+}
+
+#[test]
+fn test_undo_only_reverts_own_edits() {
+    let mut doc = CRDTDocument::new("doc-7".to_string());
+    doc.update_content("Hello", "alice");
+    doc.update_content("Hello world", "bob");
+
+    let result = doc.undo("alice");
+    assert_eq!(result.update.unwrap().content, " world");
+    assert!(!result.can_undo);
+    assert!(result.can_redo);
+
+    let result = doc.redo("alice");
+    assert_eq!(result.update.unwrap().content, "Hello world");
+
+    assert_eq!(doc.undo("bob").update.unwrap().content, "Hello");
+    // Nothing of carol's to undo
+    assert!(doc.undo("carol").update.is_none());
+}
+
+#[test]
+fn test_undo_produces_update_for_replicas() {
+    let mut doc = CRDTDocument::new("doc-8".to_string());
+    doc.update_content("Hello", "alice");
+    let mut replica = replica_of(&doc, "doc-8");
+
+    let undo = doc.undo("alice").update.unwrap();
+    replica.apply_update(&undo).unwrap();
+    assert_eq!(replica.get_content(), "");
+    assert_eq!(doc.get_state().version, 2);
+}
+
+#[test]
+fn test_undo_history_is_kept_for_recent_users_only() {
+    let mut doc = CRDTDocument::new("doc-10".to_string());
+    let mut content = String::new();
+    for i in 0..100 {
+        content.push('x');
+        doc.update_content(&content, &format!("user{}", i));
+    }
+
+    // Long-gone users' edits can't be undone any more, recent ones' still can
+    assert!(doc.undo("user0").update.is_none());
+    assert!(doc.undo("user99").update.is_some());
+}
+
+#[test]
+fn test_merged_updates_are_not_undoable_here() {
+    let mut doc = CRDTDocument::new("doc-11".to_string());
+    doc.update_content("Hello", "alice");
+
+    // Edits other instances made, some of them under the same user
+    let mut elsewhere = replica_of(&doc, "doc-11");
+    let mut content = "Hello".to_string();
+    for i in 0..100 {
+        content.push('x');
+        doc.merge_update(&elsewhere.update_content(&content, &format!("user{}", i))).unwrap();
+    }
+    doc.merge_update(&elsewhere.update_content("Hello!", "alice")).unwrap();
+
+    // alice's history survives them all, and holds only her own edit from here
+    assert!(doc.undo("user99").update.is_none());
+    assert_eq!(doc.undo("alice").update.unwrap().content, "!");
+}
@@ -262,7 +262,7 @@ async fn test_multiple_updates() {
     let document_id = create_body.id;
     
     // Make multiple updates
-    let updates = vec!["First update", "Second update", "Third update"];
+    let updates = ["First update", "Second update", "Third update"];
     
     for content in updates.iter() {
         let update_response = server
@@ -329,7 +329,7 @@ async fn test_concurrent_access() {
     let document_id = create_body.id;
     
     // Simulate sequential updates (since TestServer doesn't support cloning)
-    let updates = vec!["Update 0", "Update 1", "Update 2", "Update 3", "Update 4"];
+    let updates = ["Update 0", "Update 1", "Update 2", "Update 3", "Update 4"];
     
     for content in updates.iter() {
         let update_response = server
@@ -362,6 +362,7 @@ async fn test_concurrent_access() {
 #[tokio::test]
 async fn test_crdt_document_creation() {
     let mut doc = CRDTDocument::new("test-doc-123".to_string());
+    assert_eq!(doc.id, "test-doc-123");
     
     // Test initial state
     let state = doc.get_state();